@@ -1,21 +1,70 @@
 use core::fmt;
+#[cfg(feature = "threads")]
+use rayon::prelude::*;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     hash::{Hash, Hasher},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard, TryLockError,
+    },
 };
 use uuid::Uuid;
 
-#[derive(Debug)]
-enum Operation {
+/// The operation that produced a node. Ops with a constant operand carry it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
     Init,
+    Const,
     Add,
     Sub,
     Mul,
+    MulAdd,
     Neg,
     Div,
-    Pow,
+    Pow(f32),
+    Powi(i32),
+    Sqrt,
     ReLU,
+    ReLU6,
+    Exp,
+    Tanh,
+    Sigmoid,
+    Softplus,
+    Sin,
+    Cos,
+    Sinh,
+    Cosh,
+    Max,
+}
+
+impl Operation {
+    /// Number of operands a node with this operation has.
+    fn n_operands(self) -> usize {
+        match self {
+            Operation::Init | Operation::Const => 0,
+            Operation::Add
+            | Operation::Sub
+            | Operation::Mul
+            | Operation::Neg
+            | Operation::Div
+            | Operation::Max => 2,
+            Operation::MulAdd => 3,
+            Operation::Pow(_)
+            | Operation::Powi(_)
+            | Operation::Sqrt
+            | Operation::ReLU
+            | Operation::ReLU6
+            | Operation::Exp
+            | Operation::Tanh
+            | Operation::Sigmoid
+            | Operation::Softplus
+            | Operation::Sin
+            | Operation::Cos
+            | Operation::Sinh
+            | Operation::Cosh => 1,
+        }
+    }
 }
 
 /// A differentiable scalar value.
@@ -24,19 +73,54 @@ pub struct Value {
     hash: Uuid,
     data: f32,
     grad: f32,
-    backward: Option<Box<dyn FnOnce() -> () + Send>>,
-    previous: HashSet<Parameter>,
+    backward: Option<Box<dyn FnOnce() + Send>>,
+    previous: Vec<Parameter>,
     op: Operation,
+    requires_grad: bool,
+    shared: Option<Arc<Mutex<f32>>>,
+}
+
+/// Logistic sigmoid that never evaluates `exp` of a large positive number,
+/// so it saturates to exactly 0 or 1 instead of producing NaN.
+pub(crate) fn sigmoid(x: f32) -> f32 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
+/// Locks `mutex`, recovering the data if another thread panicked while
+/// holding it, so a single bad sample doesn't poison the whole model.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Bits of the global gradient clip bound, infinite when clipping is off.
+static GRAD_CLIP: AtomicU32 = AtomicU32::new(f32::INFINITY.to_bits());
+
+/// Clamps every gradient contribution made during backpropagation to
+/// `[-value, value]` for all graphs and threads, until `clear_grad_clip`.
+/// Applies to `backward`, `backward_parallel`, `backward_for`,
+/// `backward_fresh` and `accumulate_grad`, but not to `Tape`.
+pub fn set_grad_clip(value: f32) {
+    assert!(value > 0.0, "clip value must be positive");
+    GRAD_CLIP.store(value.to_bits(), Ordering::Relaxed);
+}
+
+/// Turns off the clipping enabled by `set_grad_clip`.
+pub fn clear_grad_clip() {
+    GRAD_CLIP.store(f32::INFINITY.to_bits(), Ordering::Relaxed);
 }
 
 fn build_topo(param: Parameter, topo: &mut Vec<Parameter>, visited: &mut HashSet<Uuid>) {
-    let hash = param.0.lock().unwrap().hash;
+    let hash = lock(&param.0).hash;
     if !visited.contains(&hash) {
         visited.insert(hash);
-        param
-            .0
-            .lock()
-            .unwrap()
+        lock(&param.0)
             .previous
             .iter()
             .for_each(|child| build_topo(child.clone(), topo, visited));
@@ -57,11 +141,16 @@ impl Value {
             data,
             grad: 0.0,
             backward: None,
-            previous: HashSet::new(),
+            previous: vec![],
             op: Operation::Init,
+            requires_grad: true,
+            shared: None,
         })))
     }
-    fn new(data: f32, previous: HashSet<Parameter>, op: Operation) -> Value {
+    /// Node computed from `previous`. It only requires a gradient if one of
+    /// its operands does.
+    fn new(data: f32, previous: Vec<Parameter>, op: Operation) -> Value {
+        let requires_grad = previous.iter().any(|p| p.requires_grad());
         Value {
             hash: Uuid::new_v4(),
             data,
@@ -69,6 +158,28 @@ impl Value {
             backward: None,
             previous,
             op,
+            requires_grad,
+            shared: None,
+        }
+    }
+    /// Stores the backward step of an op node. Nodes that don't require a
+    /// gradient never run it, so it isn't boxed for them at all.
+    fn set_backward(&mut self, backward: impl FnOnce() + Send + 'static) {
+        if self.requires_grad {
+            self.backward = Some(Box::new(backward));
+        }
+    }
+    /// Current data, read through the external buffer for shared leaves.
+    fn get_data(&self) -> f32 {
+        match &self.shared {
+            Some(shared) => *lock(shared),
+            None => self.data,
+        }
+    }
+    fn set_data(&mut self, data: f32) {
+        match &self.shared {
+            Some(shared) => *lock(shared) = data,
+            None => self.data = data,
         }
     }
 }
@@ -77,87 +188,440 @@ impl Parameter {
     pub fn from_scalar(scalar: f32) -> Parameter {
         Value::from_scalar(scalar)
     }
+    /// Trainable leaf that starts out with gradient `grad`, e.g. to check in
+    /// a test that an op's backward step adds to an existing gradient.
+    pub fn with_grad(data: f32, grad: f32) -> Parameter {
+        let param = Value::from_scalar(data);
+        lock(&param.0).grad = grad;
+        param
+    }
+    /// Non-trainable leaf, e.g. a fixed coefficient. Marked as `Const` in
+    /// graph dumps and skipped by `lr_step`.
+    pub fn constant(scalar: f32) -> Parameter {
+        let param = Value::from_scalar(scalar);
+        {
+            let mut param_ref = lock(&param.0);
+            param_ref.op = Operation::Const;
+            param_ref.requires_grad = false;
+        }
+        param
+    }
+    /// Leaf whose data lives in an external buffer, e.g. one owned by an
+    /// external optimizer. Forward passes read the buffer's current value and
+    /// `set_data`/`lr_step` write back into it.
+    pub fn from_shared(shared: Arc<Mutex<f32>>) -> Parameter {
+        let param = Value::from_scalar(*lock(&shared));
+        lock(&param.0).shared = Some(shared);
+        param
+    }
+    /// Like `from_scalar` but returns `None` for NaN or infinite input,
+    /// which would otherwise silently poison all downstream math.
+    pub fn try_from_scalar(scalar: f32) -> Option<Parameter> {
+        if scalar.is_finite() {
+            Some(Value::from_scalar(scalar))
+        } else {
+            None
+        }
+    }
     /// Passes Parameter through ReLU.
     pub fn relu(self) -> Parameter {
-        let data = self.0.lock().unwrap().data;
+        let data = lock(&self.0).get_data();
         let out = Value::new(
             if data < 0.0 { 0.0 } else { data },
-            HashSet::from([self.clone()]),
+            vec![self.clone()],
             Operation::ReLU,
         );
         let out = Arc::new(Mutex::new(out));
         let out_ref = Arc::clone(&out);
 
-        out.lock().unwrap().backward = Some(Box::new(move || {
-            let out_ref = out_ref.lock().unwrap();
+        lock(&out).set_backward(move || {
+            let out_ref = lock(&out_ref);
             let out_data = out_ref.data;
             let out_grad = out_ref.grad;
-            self.0.lock().unwrap().grad += if out_data > 0.0 { out_grad } else { 0.0 }
-        }));
+            self.accumulate_grad(if out_data > 0.0 { out_grad } else { 0.0 });
+        });
         Parameter(out)
     }
-    /// Set Parameter gradient to zero.
-    pub fn zero_grad(&mut self) -> () {
-        self.0.lock().unwrap().grad = 0.0;
+    /// Passes Parameter through ReLU without consuming it.
+    pub fn relu_ref(&self) -> Parameter {
+        self.clone().relu()
     }
-    /// Increase reference count of this Parameter.
-    pub fn clone(&self) -> Parameter {
-        Parameter(Arc::clone(&self.0))
+    /// Set Parameter gradient to zero.
+    pub fn zero_grad(&mut self) {
+        lock(&self.0).grad = 0.0;
     }
     /// Performs a backward pass on the Parameter if it's defined.
-    fn _backward(&self) -> () {
-        let try_backward = self.0.lock().unwrap().backward.take();
-        match try_backward {
-            Some(back) => back(),
-            None => (),
+    /// Nodes with only frozen inputs are skipped, their closure is just dropped.
+    fn _backward(&self) {
+        let try_backward = {
+            let mut self_ref = lock(&self.0);
+            let back = self_ref.backward.take();
+            back.filter(|_| self_ref.requires_grad)
+        };
+        if let Some(back) = try_backward {
+            back();
+        }
+    }
+    /// Takes the local backward step out of this node, for driving backward
+    /// passes by hand: after setting this node's gradient, calling the
+    /// returned `GradFn` adds its contribution to the node's operands. The
+    /// step is removed from the node, so a later `backward` won't run it
+    /// again. `None` for leaves, frozen nodes and nodes whose step already ran.
+    pub fn take_grad_fn(&self) -> Option<GradFn> {
+        let mut self_ref = lock(&self.0);
+        let back = self_ref.backward.take();
+        back.filter(|_| self_ref.requires_grad).map(GradFn)
+    }
+    /// Adds `delta` to the gradient unless the Parameter is frozen. This is
+    /// what the backward closures of the built-in ops use, so custom ops can
+    /// be implemented outside the crate with it. `delta` is clamped when
+    /// `set_grad_clip` is active.
+    pub fn accumulate_grad(&self, delta: f32) {
+        let clip = f32::from_bits(GRAD_CLIP.load(Ordering::Relaxed));
+        let mut self_ref = lock(&self.0);
+        if self_ref.requires_grad {
+            self_ref.grad += delta.clamp(-clip, clip);
         }
     }
     /// Initiates a recursive backward pass from this Parameter through the
     /// computation graph in topological order.
-    pub fn backward(&self) -> () {
+    pub fn backward(&self) {
+        backward_multi(std::slice::from_ref(self));
+    }
+    /// Like `backward` but first resets the gradient of every node in the
+    /// graph, so repeated calls give the same gradients instead of adding up.
+    /// Gradients are recomputed from the recorded data and operations (see
+    /// `Tape`), so this also works after an earlier backward pass. Like
+    /// `backward` it stops at frozen nodes and applies `set_grad_clip`.
+    pub fn backward_fresh(&self) {
+        let mut topo_nodes: Vec<Parameter> = vec![];
+        let mut visited_nodes: HashSet<Uuid> = HashSet::new();
+        build_topo(self.clone(), &mut topo_nodes, &mut visited_nodes);
+
+        let tape = to_tape(self);
+        let values: Vec<f32> = topo_nodes.iter().map(|node| node.data()).collect();
+        let trainable: Vec<bool> = topo_nodes.iter().map(|node| node.requires_grad()).collect();
+        let clip = f32::from_bits(GRAD_CLIP.load(Ordering::Relaxed));
+        let grads = tape.backward_with(&values, Some(&trainable), clip);
+        for (node, grad) in topo_nodes.iter().zip(grads) {
+            let mut node_ref = lock(&node.0);
+            // the closures would only add to the gradients set here
+            node_ref.backward = None;
+            node_ref.grad = if node_ref.requires_grad { grad } else { 0.0 };
+        }
+        lock(&self.0).grad = 1.0;
+    }
+    /// Like `backward` but only runs the nodes that have one of `targets`
+    /// among their ancestors. Gradients are only guaranteed to be correct for
+    /// `targets` and the nodes between them and this root; branches that
+    /// can't reach a target are pruned and their leaves keep their gradient.
+    pub fn backward_for(&self, targets: &[Parameter]) {
+        let mut topo_nodes: Vec<Parameter> = vec![];
+        let mut visited_nodes: HashSet<Uuid> = HashSet::new();
+        build_topo(self.clone(), &mut topo_nodes, &mut visited_nodes);
+
+        let mut reaches_target: HashSet<Uuid> = targets.iter().map(|t| lock(&t.0).hash).collect();
+        // children come before their consumers in topological order
+        for node in topo_nodes.iter() {
+            let node_ref = lock(&node.0);
+            if node_ref
+                .previous
+                .iter()
+                .any(|child| reaches_target.contains(&lock(&child.0).hash))
+            {
+                reaches_target.insert(node_ref.hash);
+            }
+        }
+
+        lock(&self.0).grad = 1.0;
+        topo_nodes
+            .iter()
+            .rev()
+            .filter(|node| reaches_target.contains(&lock(&node.0).hash))
+            .for_each(|node| node._backward());
+    }
+    /// Like `backward` but runs independent nodes concurrently. Nodes are
+    /// grouped by their longest distance from this root; a node's consumers
+    /// are always strictly closer to the root, so each group only runs after
+    /// all gradient contributions to it are in. Gradients may differ from
+    /// `backward` by float rounding since accumulation order is not fixed.
+    #[cfg(feature = "threads")]
+    pub fn backward_parallel(&self) {
         let mut topo_nodes: Vec<Parameter> = vec![];
         let mut visited_nodes: HashSet<Uuid> = HashSet::new();
         build_topo(self.clone(), &mut topo_nodes, &mut visited_nodes);
-        self.0.lock().unwrap().grad = 1.0;
-        topo_nodes.iter().rev().for_each(|value| value._backward());
+
+        let mut depths: HashMap<Uuid, usize> = HashMap::new();
+        let mut levels: Vec<Vec<Parameter>> = vec![];
+        for node in topo_nodes.iter().rev() {
+            let node_ref = lock(&node.0);
+            let depth = *depths.entry(node_ref.hash).or_insert(0);
+            for child in node_ref.previous.iter() {
+                let child_depth = depths.entry(lock(&child.0).hash).or_insert(0);
+                *child_depth = (*child_depth).max(depth + 1);
+            }
+            if levels.len() <= depth {
+                levels.resize(depth + 1, vec![]);
+            }
+            levels[depth].push(node.clone());
+        }
+
+        lock(&self.0).grad = 1.0;
+        for level in levels {
+            level.par_iter().for_each(|node| node._backward());
+        }
     }
     /// Raises Parameter to power of `power`.
+    /// A negative base with a fractional power yields NaN both here and in
+    /// the backward pass; use `checked_pow` to catch that case.
     pub fn pow(self, power: f32) -> Parameter {
-        let data = self.0.lock().unwrap().data;
+        let data = lock(&self.0).get_data();
+        let out = Value::new(data.powf(power), vec![self.clone()], Operation::Pow(power));
+        let out = Arc::new(Mutex::new(out));
+        let out_ref = Arc::clone(&out);
+
+        lock(&out).set_backward(move || {
+            let out_grad = lock(&out_ref).grad;
+            self.accumulate_grad((power * data.powf(power - 1.0)) * out_grad);
+        });
+        Parameter(out)
+    }
+    /// Raises Parameter to the integer power `n`. Faster and more precise
+    /// than `pow` for small exponents such as squaring.
+    pub fn powi(self, n: i32) -> Parameter {
+        let data = self.data();
+        self.unary_op(
+            data.powi(n),
+            n as f32 * data.powi(n - 1),
+            Operation::Powi(n),
+        )
+    }
+    /// Like `pow` but returns `None` if either the result or its derivative
+    /// would not be finite, e.g. a negative base with a fractional power.
+    pub fn checked_pow(self, power: f32) -> Option<Parameter> {
+        let data = self.data();
+        if data.powf(power).is_finite() && (power * data.powf(power - 1.0)).is_finite() {
+            Some(self.pow(power))
+        } else {
+            None
+        }
+    }
+    /// Square root of the Parameter. The gradient is infinite at zero.
+    pub fn sqrt(self) -> Parameter {
+        let out = self.pow(0.5);
+        lock(&out.0).op = Operation::Sqrt;
+        out
+    }
+    /// Raises Parameter to power of `power` without consuming it.
+    pub fn pow_ref(&self, power: f32) -> Parameter {
+        self.clone().pow(power)
+    }
+    /// Fused `self * mul + add` as a single node, saving the intermediate
+    /// product node of the unfused version.
+    pub fn mul_add(self, mul: Parameter, add: Parameter) -> Parameter {
+        let self_data = self.data();
+        let mul_data = mul.data();
+        let add_data = add.data();
         let out = Value::new(
-            data.powf(power),
-            HashSet::from([self.clone()]),
-            Operation::Pow,
+            self_data.mul_add(mul_data, add_data),
+            vec![self.clone(), mul.clone(), add.clone()],
+            Operation::MulAdd,
         );
         let out = Arc::new(Mutex::new(out));
         let out_ref = Arc::clone(&out);
 
-        out.lock().unwrap().backward = Some(Box::new(move || {
-            let mut self_ref = self.0.lock().unwrap();
-            let out_grad = out_ref.lock().unwrap().grad;
-            self_ref.grad += (power * self_ref.data.powf(power - 1.0)) * out_grad;
-        }));
+        lock(&out).set_backward(move || {
+            let out_grad = lock(&out_ref).grad;
+            self.accumulate_grad(mul_data * out_grad);
+            mul.accumulate_grad(self_data * out_grad);
+            add.accumulate_grad(out_grad);
+        });
+        Parameter(out)
+    }
+    /// Builds a single-input node with output `data`. Its backward pass
+    /// scales the output gradient by `local_grad`, the derivative of the op
+    /// at the input.
+    fn unary_op(self, data: f32, local_grad: f32, op: Operation) -> Parameter {
+        let out = Value::new(data, vec![self.clone()], op);
+        let out = Arc::new(Mutex::new(out));
+        let out_ref = Arc::clone(&out);
+
+        lock(&out).set_backward(move || {
+            let out_grad = lock(&out_ref).grad;
+            self.accumulate_grad(local_grad * out_grad);
+        });
+        Parameter(out)
+    }
+    /// Passes Parameter through ReLU6, `min(max(0, x), 6)`.
+    pub fn relu6(self) -> Parameter {
+        let data = self.data();
+        let local_grad = if data > 0.0 && data < 6.0 { 1.0 } else { 0.0 };
+        self.unary_op(data.clamp(0.0, 6.0), local_grad, Operation::ReLU6)
+    }
+    /// Exponential of the Parameter.
+    pub fn exp(self) -> Parameter {
+        let out = self.data().exp();
+        self.unary_op(out, out, Operation::Exp)
+    }
+    /// Passes Parameter through tanh.
+    pub fn tanh(self) -> Parameter {
+        let out = self.data().tanh();
+        self.unary_op(out, 1.0 - out * out, Operation::Tanh)
+    }
+    /// Passes Parameter through the logistic sigmoid.
+    pub fn sigmoid(self) -> Parameter {
+        let out = sigmoid(self.data());
+        self.unary_op(out, out * (1.0 - out), Operation::Sigmoid)
+    }
+    /// Passes Parameter through softplus, `ln(1 + exp(x))`, a smooth ReLU.
+    pub fn softplus(self) -> Parameter {
+        let data = self.data();
+        // rearranged so that exp never overflows
+        let out = data.max(0.0) + (-data.abs()).exp().ln_1p();
+        self.unary_op(out, sigmoid(data), Operation::Softplus)
+    }
+    /// Sine of the Parameter.
+    pub fn sin(self) -> Parameter {
+        let data = self.data();
+        self.unary_op(data.sin(), data.cos(), Operation::Sin)
+    }
+    /// Cosine of the Parameter.
+    pub fn cos(self) -> Parameter {
+        let data = self.data();
+        self.unary_op(data.cos(), -data.sin(), Operation::Cos)
+    }
+    /// Hyperbolic sine of the Parameter.
+    pub fn sinh(self) -> Parameter {
+        let data = self.data();
+        self.unary_op(data.sinh(), data.cosh(), Operation::Sinh)
+    }
+    /// Hyperbolic cosine of the Parameter.
+    pub fn cosh(self) -> Parameter {
+        let data = self.data();
+        self.unary_op(data.cosh(), data.sinh(), Operation::Cosh)
+    }
+    /// The larger of two Parameters. The gradient goes to the larger one
+    /// only, to `self` on ties.
+    pub fn max(self, other: Parameter) -> Parameter {
+        let self_data = self.data();
+        let other_data = other.data();
+        let self_wins = self_data >= other_data;
+        let out = Value::new(
+            if self_wins { self_data } else { other_data },
+            vec![self.clone(), other.clone()],
+            Operation::Max,
+        );
+        let out = Arc::new(Mutex::new(out));
+        let out_ref = Arc::clone(&out);
+
+        lock(&out).set_backward(move || {
+            let out_grad = lock(&out_ref).grad;
+            if self_wins {
+                self.accumulate_grad(out_grad);
+            } else {
+                other.accumulate_grad(out_grad);
+            }
+        });
         Parameter(out)
     }
     pub fn data(&self) -> f32 {
-        self.0.lock().unwrap().data
+        lock(&self.0).get_data()
+    }
+    /// Mask that is 1.0 if `self` is greater than `other` and 0.0 otherwise.
+    /// The result is a constant, so no gradient flows back to either side;
+    /// multiply by it to build piecewise functions.
+    pub fn gt(&self, other: &Parameter) -> Parameter {
+        Parameter::constant(if self.data() > other.data() { 1.0 } else { 0.0 })
+    }
+    /// True for Parameters that weren't computed from others, i.e. inputs,
+    /// weights and constants.
+    pub fn is_leaf(&self) -> bool {
+        lock(&self.0).previous.is_empty()
+    }
+    /// True while the node holds a backward step that hasn't run yet. Leaves
+    /// and nodes that don't require a gradient never hold one.
+    pub fn has_grad_fn(&self) -> bool {
+        lock(&self.0).backward.is_some()
+    }
+    /// The operation that produced this Parameter.
+    pub fn op(&self) -> Operation {
+        lock(&self.0).op
+    }
+    pub fn grad(&self) -> f32 {
+        lock(&self.0).grad
+    }
+    /// Compares the data of two Parameters within `eps`. Unlike `==`, which
+    /// checks whether both refer to the same graph node, this compares values.
+    pub fn approx_eq(&self, other: &Parameter, eps: f32) -> bool {
+        (self.data() - other.data()).abs() <= eps
+    }
+    /// Returns false if either the data or the gradient is NaN or infinite.
+    pub fn is_finite(&self) -> bool {
+        let self_ref = lock(&self.0);
+        self_ref.get_data().is_finite() && self_ref.grad.is_finite()
     }
-    pub fn lr_step(&mut self, new_lr: f32) -> () {
-        let mut self_ref = self.0.lock().unwrap();
-        self_ref.data -= new_lr * self_ref.grad;
+    /// Overwrites the data of the Parameter.
+    pub fn set_data(&self, data: f32) {
+        lock(&self.0).set_data(data);
+    }
+    /// Applies `f` to the data of the Parameter in place. This edits the
+    /// value directly and does not add a node to the graph.
+    pub fn map_data(&self, f: impl FnOnce(f32) -> f32) {
+        let mut self_ref = lock(&self.0);
+        let data = f(self_ref.get_data());
+        self_ref.set_data(data);
+    }
+    /// Applies `f` to the gradient of the Parameter in place.
+    pub fn map_grad(&self, f: impl FnOnce(f32) -> f32) {
+        let mut self_ref = lock(&self.0);
+        self_ref.grad = f(self_ref.grad);
+    }
+    /// Marks the Parameter as trainable or frozen.
+    /// Frozen Parameters are left untouched by `lr_step` and receive no
+    /// gradient. Only affects graphs built after the call.
+    pub fn set_requires_grad(&self, requires_grad: bool) {
+        lock(&self.0).requires_grad = requires_grad;
+    }
+    /// False for frozen Parameters, constants and nodes computed only from
+    /// those.
+    pub fn requires_grad(&self) -> bool {
+        lock(&self.0).requires_grad
+    }
+    pub fn lr_step(&mut self, new_lr: f32) {
+        let mut self_ref = lock(&self.0);
+        if self_ref.requires_grad {
+            let data = self_ref.get_data() - new_lr * self_ref.grad;
+            self_ref.set_data(data);
+        }
+    }
+}
+
+/// The local backward step of a single node, see `Parameter::take_grad_fn`.
+pub struct GradFn(Box<dyn FnOnce() + Send>);
+
+impl GradFn {
+    /// Propagates the node's current gradient to its operands.
+    pub fn call(self) {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for GradFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("GradFn")
     }
 }
 
 impl Hash for Parameter {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        let borrow = self.0.lock().unwrap();
+        let borrow = lock(&self.0);
         borrow.hash.hash(state);
     }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
-        self.data == other.data
+        self.get_data() == other.get_data()
     }
 }
 
@@ -165,11 +629,12 @@ impl Eq for Value {}
 
 impl PartialEq for Parameter {
     fn eq(&self, other: &Self) -> bool {
-        let borrow = self.0.lock().unwrap();
+        let borrow = lock(&self.0);
         let try_borrow_other = other.0.try_lock();
         match try_borrow_other {
             Ok(borrow_other) => borrow.hash == borrow_other.hash,
-            Err(_) => true, // if referencing two same objects locking other would deadlock
+            Err(TryLockError::Poisoned(poisoned)) => borrow.hash == poisoned.into_inner().hash,
+            Err(TryLockError::WouldBlock) => true, // if referencing two same objects locking other would deadlock
         }
     }
 }
@@ -177,47 +642,48 @@ impl PartialEq for Parameter {
 impl std::ops::Add for Parameter {
     type Output = Self;
     fn add(self, other: Self) -> Self {
-        let self_data = self.0.lock().unwrap().data;
-        let other_data = other.0.lock().unwrap().data;
+        let self_data = lock(&self.0).get_data();
+        let other_data = lock(&other.0).get_data();
 
         let out = Value::new(
             self_data + other_data,
-            HashSet::from([self.clone(), other.clone()]),
+            vec![self.clone(), other.clone()],
             Operation::Add,
         );
 
         let out = Arc::new(Mutex::new(out));
         let out_ref = Arc::clone(&out);
 
-        out.lock().unwrap().backward = Some(Box::new(move || {
-            let out_grad = out_ref.lock().unwrap().grad;
-            self.0.lock().unwrap().grad += out_grad;
-            other.0.lock().unwrap().grad += out_grad;
-        }));
+        lock(&out).set_backward(move || {
+            let out_grad = lock(&out_ref).grad;
+            self.accumulate_grad(out_grad);
+            other.accumulate_grad(out_grad);
+        });
         Parameter(out)
     }
 }
 
 impl std::ops::Mul for Parameter {
     type Output = Self;
+    #[allow(clippy::suspicious_arithmetic_impl)]
     fn mul(self, other: Self) -> Self {
-        let self_data = self.0.lock().unwrap().data;
-        let other_data = other.0.lock().unwrap().data;
+        let self_data = lock(&self.0).get_data();
+        let other_data = lock(&other.0).get_data();
 
         let out = Value::new(
             self_data * other_data,
-            HashSet::from([self.clone(), other.clone()]),
+            vec![self.clone(), other.clone()],
             Operation::Mul,
         );
 
         let out = Arc::new(Mutex::new(out));
         let out_ref = Arc::clone(&out);
 
-        out.lock().unwrap().backward = Some(Box::new(move || {
-            let out_grad = out_ref.lock().unwrap().grad;
-            self.0.lock().unwrap().grad += other_data * out_grad;
-            other.0.lock().unwrap().grad += self_data * out_grad;
-        }));
+        lock(&out).set_backward(move || {
+            let out_grad = lock(&out_ref).grad;
+            self.accumulate_grad(other_data * out_grad);
+            other.accumulate_grad(self_data * out_grad);
+        });
         Parameter(out)
     }
 }
@@ -225,8 +691,8 @@ impl std::ops::Mul for Parameter {
 impl std::ops::Neg for Parameter {
     type Output = Self;
     fn neg(self) -> Self {
-        let out = self * Value::from_scalar(-1.0);
-        out.0.lock().unwrap().op = Operation::Neg;
+        let out = self * Parameter::constant(-1.0);
+        lock(&out.0).op = Operation::Neg;
         out
     }
 }
@@ -235,7 +701,7 @@ impl std::ops::Sub for Parameter {
     type Output = Self;
     fn sub(self, other: Self) -> Self {
         let out = self + (-other);
-        out.0.lock().unwrap().op = Operation::Sub;
+        lock(&out.0).op = Operation::Sub;
         out
     }
 }
@@ -244,20 +710,32 @@ impl std::ops::Div for Parameter {
     type Output = Self;
     fn div(self, other: Self) -> Self {
         let out = self * other.pow(-1.0);
-        out.0.lock().unwrap().op = Operation::Div;
+        lock(&out.0).op = Operation::Div;
         out
     }
 }
 
 impl Eq for Parameter {}
 
+impl std::iter::Sum for Parameter {
+    fn sum<I: Iterator<Item = Parameter>>(iter: I) -> Parameter {
+        iter.fold(Parameter::constant(0.0), |acc, param| acc + param)
+    }
+}
+
+impl std::iter::Product for Parameter {
+    fn product<I: Iterator<Item = Parameter>>(iter: I) -> Parameter {
+        iter.fold(Parameter::constant(1.0), |acc, param| acc * param)
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "id: {}, data: {}, grad: {}, op: {:?}",
-            self.hash.to_string(),
-            self.data,
+            self.hash,
+            self.get_data(),
             self.grad,
             self.op
         )
@@ -267,18 +745,571 @@ impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Value")
             .field("hash", &self.hash)
-            .field("data", &self.data)
+            .field("data", &self.get_data())
             .field("grad", &self.grad)
             .finish()
     }
 }
 
+/// Backward pass from several roots at once, as if from their sum. Each root
+/// is seeded with a gradient of 1.0 and the union of their graphs is walked
+/// in a single reverse topological pass, so shared subgraphs are handled once.
+pub fn backward_multi(roots: &[Parameter]) {
+    let mut topo_nodes: Vec<Parameter> = vec![];
+    let mut visited_nodes: HashSet<Uuid> = HashSet::new();
+    for root in roots {
+        build_topo(root.clone(), &mut topo_nodes, &mut visited_nodes);
+    }
+    for root in roots {
+        lock(&root.0).grad = 1.0;
+    }
+    topo_nodes.iter().rev().for_each(|value| value._backward());
+}
+
+/// Renders the graph ending in `root` in Graphviz dot format. Each node is
+/// labeled with its operation, data and gradient; trainable leaves show up as
+/// `Init` and constants as `Const`.
+pub fn to_dot(root: &Parameter) -> String {
+    let mut topo_nodes: Vec<Parameter> = vec![];
+    let mut visited_nodes: HashSet<Uuid> = HashSet::new();
+    build_topo(root.clone(), &mut topo_nodes, &mut visited_nodes);
+
+    let mut dot = String::from("digraph {\n    rankdir=LR;\n");
+    for node in topo_nodes.iter() {
+        let node_ref = lock(&node.0);
+        dot.push_str(&format!(
+            "    \"{}\" [shape=record, label=\"{:?} | data {:.4} | grad {:.4}\"];\n",
+            node_ref.hash,
+            node_ref.op,
+            node_ref.get_data(),
+            node_ref.grad
+        ));
+        for child in node_ref.previous.iter() {
+            dot.push_str(&format!(
+                "    \"{}\" -> \"{}\";\n",
+                lock(&child.0).hash,
+                node_ref.hash
+            ));
+        }
+    }
+    dot.push('}');
+    dot
+}
+
+/// A node of a `SerializedGraph`. `operands` are ids of earlier nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerializedNode {
+    pub id: usize,
+    pub op: Operation,
+    pub data: f32,
+    pub operands: Vec<usize>,
+}
+
+/// Structure of a computation graph in topological order, root last.
+/// Displays as one `id op data operands` line per node and can be read back
+/// with `parse`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerializedGraph {
+    pub nodes: Vec<SerializedNode>,
+}
+
+/// Records the graph ending in `root`. Node ids are positions in the
+/// topological order.
+pub fn serialize_graph(root: &Parameter) -> SerializedGraph {
+    let mut topo_nodes: Vec<Parameter> = vec![];
+    let mut visited_nodes: HashSet<Uuid> = HashSet::new();
+    build_topo(root.clone(), &mut topo_nodes, &mut visited_nodes);
+
+    let ids: HashMap<Uuid, usize> = topo_nodes
+        .iter()
+        .enumerate()
+        .map(|(id, node)| (lock(&node.0).hash, id))
+        .collect();
+    let nodes = topo_nodes
+        .iter()
+        .enumerate()
+        .map(|(id, node)| {
+            let node_ref = lock(&node.0);
+            SerializedNode {
+                id,
+                op: node_ref.op,
+                data: node_ref.get_data(),
+                operands: node_ref
+                    .previous
+                    .iter()
+                    .map(|child| ids[&lock(&child.0).hash])
+                    .collect(),
+            }
+        })
+        .collect();
+    SerializedGraph { nodes }
+}
+
+/// Rebuilds a recorded graph. Leaves get their recorded data and every other
+/// node is recomputed from its operands, so `backward` can be run on the
+/// root (the last returned Parameter) again. Returns the nodes in id order.
+pub fn replay(graph: &SerializedGraph) -> Vec<Parameter> {
+    let mut nodes: Vec<Parameter> = Vec::with_capacity(graph.nodes.len());
+    for node in graph.nodes.iter() {
+        let operands: Vec<Parameter> = node.operands.iter().map(|i| nodes[*i].clone()).collect();
+        nodes.push(apply_op(node.op, &operands, node.data));
+    }
+    nodes
+}
+
+/// Builds a node computing `op` from `operands`, labeled `op`. Leaves are
+/// created with `data`.
+fn apply_op(op: Operation, operands: &[Parameter], data: f32) -> Parameter {
+    let operand = |i: usize| operands[i].clone();
+    let out = match op {
+        Operation::Init => Value::from_scalar(data),
+        Operation::Const => Parameter::constant(data),
+        // Sub, Neg and Div are relabeled Add, Mul and Mul nodes
+        Operation::Add | Operation::Sub => operand(0) + operand(1),
+        Operation::Mul | Operation::Neg | Operation::Div => operand(0) * operand(1),
+        Operation::MulAdd => operand(0).mul_add(operand(1), operand(2)),
+        Operation::Pow(power) => operand(0).pow(power),
+        Operation::Powi(n) => operand(0).powi(n),
+        Operation::Sqrt => operand(0).sqrt(),
+        Operation::ReLU => operand(0).relu(),
+        Operation::ReLU6 => operand(0).relu6(),
+        Operation::Exp => operand(0).exp(),
+        Operation::Tanh => operand(0).tanh(),
+        Operation::Sigmoid => operand(0).sigmoid(),
+        Operation::Softplus => operand(0).softplus(),
+        Operation::Sin => operand(0).sin(),
+        Operation::Cos => operand(0).cos(),
+        Operation::Sinh => operand(0).sinh(),
+        Operation::Cosh => operand(0).cosh(),
+        Operation::Max => operand(0).max(operand(1)),
+    };
+    lock(&out.0).op = op;
+    out
+}
+
+/// Returns an equivalent graph in which every subgraph computed only from
+/// constants (`Parameter::constant`) is replaced by a single constant
+/// holding its value. Nodes that don't consume a folded subgraph are reused
+/// as they are, so trainable leaves keep their identity and gradients.
+pub fn fold_constants(root: &Parameter) -> Parameter {
+    let mut topo_nodes: Vec<Parameter> = vec![];
+    let mut visited_nodes: HashSet<Uuid> = HashSet::new();
+    build_topo(root.clone(), &mut topo_nodes, &mut visited_nodes);
+
+    let mut folded: HashMap<Uuid, Parameter> = HashMap::new();
+    for node in topo_nodes.iter() {
+        let (hash, op, data, previous) = {
+            let node_ref = lock(&node.0);
+            let previous = node_ref.previous.clone();
+            (node_ref.hash, node_ref.op, node_ref.get_data(), previous)
+        };
+        let operands: Vec<Parameter> = previous
+            .iter()
+            .map(|child| folded[&lock(&child.0).hash].clone())
+            .collect();
+        let new_node = if operands.is_empty() {
+            node.clone()
+        } else if operands.iter().all(|o| o.op() == Operation::Const) {
+            Parameter::constant(data)
+        } else if operands == previous {
+            node.clone()
+        } else {
+            apply_op(op, &operands, data)
+        };
+        folded.insert(hash, new_node);
+    }
+    folded[&lock(&root.0).hash].clone()
+}
+
+impl fmt::Display for SerializedGraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for node in self.nodes.iter() {
+            let operands: Vec<String> = node.operands.iter().map(|o| o.to_string()).collect();
+            let operands = if operands.is_empty() {
+                String::from("-")
+            } else {
+                operands.join(",")
+            };
+            writeln!(f, "{} {:?} {} {}", node.id, node.op, node.data, operands)?;
+        }
+        Ok(())
+    }
+}
+
+impl SerializedGraph {
+    /// Reads a graph written by the `Display` impl. Fails unless the ids
+    /// run from 0 in order, every operand refers to an earlier node and
+    /// every node has as many operands as its operation takes, so the result
+    /// can always be passed to `replay`.
+    pub fn parse(text: &str) -> Result<SerializedGraph, String> {
+        let nodes = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() != 4 {
+                    return Err(format!("expected 4 fields in line: {}", line));
+                }
+                let id = fields[0]
+                    .parse()
+                    .map_err(|_| format!("bad id: {}", fields[0]))?;
+                let op = fields[1].parse()?;
+                let data = fields[2]
+                    .parse()
+                    .map_err(|_| format!("bad data: {}", fields[2]))?;
+                let operands = if fields[3] == "-" {
+                    vec![]
+                } else {
+                    fields[3]
+                        .split(',')
+                        .map(|o| o.parse().map_err(|_| format!("bad operand: {}", o)))
+                        .collect::<Result<Vec<usize>, String>>()?
+                };
+                Ok(SerializedNode {
+                    id,
+                    op,
+                    data,
+                    operands,
+                })
+            })
+            .collect::<Result<Vec<SerializedNode>, String>>()?;
+        for (i, node) in nodes.iter().enumerate() {
+            if node.id != i {
+                return Err(format!("expected id {} but found {}", i, node.id));
+            }
+            if let Some(operand) = node.operands.iter().find(|o| **o >= node.id) {
+                return Err(format!(
+                    "node {} refers to node {}, which doesn't come before it",
+                    node.id, operand
+                ));
+            }
+            if node.operands.len() != node.op.n_operands() {
+                return Err(format!(
+                    "node {} has {} operands but {:?} takes {}",
+                    node.id,
+                    node.operands.len(),
+                    node.op,
+                    node.op.n_operands()
+                ));
+            }
+        }
+        Ok(SerializedGraph { nodes })
+    }
+}
+
+impl std::str::FromStr for Operation {
+    type Err = String;
+    /// Parses the `Debug` representation of an Operation.
+    fn from_str(s: &str) -> Result<Operation, String> {
+        let arg = |prefix: &str| {
+            s.strip_prefix(prefix)
+                .and_then(|rest| rest.strip_suffix(')'))
+                .ok_or_else(|| format!("unknown operation: {}", s))
+        };
+        let op = match s {
+            "Init" => Operation::Init,
+            "Const" => Operation::Const,
+            "Add" => Operation::Add,
+            "Sub" => Operation::Sub,
+            "Mul" => Operation::Mul,
+            "MulAdd" => Operation::MulAdd,
+            "Neg" => Operation::Neg,
+            "Div" => Operation::Div,
+            "Sqrt" => Operation::Sqrt,
+            "ReLU" => Operation::ReLU,
+            "ReLU6" => Operation::ReLU6,
+            "Exp" => Operation::Exp,
+            "Tanh" => Operation::Tanh,
+            "Sigmoid" => Operation::Sigmoid,
+            "Softplus" => Operation::Softplus,
+            "Sin" => Operation::Sin,
+            "Cos" => Operation::Cos,
+            "Sinh" => Operation::Sinh,
+            "Cosh" => Operation::Cosh,
+            "Max" => Operation::Max,
+            _ if s.starts_with("Powi(") => Operation::Powi(
+                arg("Powi(")?
+                    .parse()
+                    .map_err(|_| format!("bad exponent: {}", s))?,
+            ),
+            _ if s.starts_with("Pow(") => Operation::Pow(
+                arg("Pow(")?
+                    .parse()
+                    .map_err(|_| format!("bad exponent: {}", s))?,
+            ),
+            _ => return Err(format!("unknown operation: {}", s)),
+        };
+        Ok(op)
+    }
+}
+
+/// One step of a `Tape`: slot `out` is `op` applied to the `operands` slots.
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub op: Operation,
+    pub operands: Vec<usize>,
+    pub out: usize,
+}
+
+/// A computation graph flattened into a linear list of instructions over
+/// numbered slots. Replaying it reads and writes plain `f32`s, without locks
+/// or pointer chasing, so it is cheap to re-run for new inputs of the same
+/// graph. Slots are numbered in topological order with the root last.
+#[derive(Debug, Clone)]
+pub struct Tape {
+    n_slots: usize,
+    /// Slot and recorded data of every trainable leaf, in slot order.
+    inputs: Vec<(usize, f32)>,
+    /// Slot and data of every constant leaf.
+    constants: Vec<(usize, f32)>,
+    instructions: Vec<Instruction>,
+}
+
+/// Flattens the graph ending in `root` into a `Tape`.
+pub fn to_tape(root: &Parameter) -> Tape {
+    let graph = serialize_graph(root);
+    let mut inputs = vec![];
+    let mut constants = vec![];
+    let mut instructions = vec![];
+    for node in graph.nodes.iter() {
+        match node.op {
+            Operation::Init => inputs.push((node.id, node.data)),
+            Operation::Const => constants.push((node.id, node.data)),
+            op => instructions.push(Instruction {
+                op,
+                operands: node.operands.clone(),
+                out: node.id,
+            }),
+        }
+    }
+    Tape {
+        n_slots: graph.nodes.len(),
+        inputs,
+        constants,
+        instructions,
+    }
+}
+
+impl Tape {
+    /// Number of trainable leaves, i.e. the length expected by `forward`.
+    pub fn n_inputs(&self) -> usize {
+        self.inputs.len()
+    }
+    /// Data the trainable leaves had when the tape was recorded.
+    pub fn recorded_inputs(&self) -> Vec<f32> {
+        self.inputs.iter().map(|(_, data)| *data).collect()
+    }
+    /// Slots of the trainable leaves, in the order `forward` takes them.
+    pub fn input_slots(&self) -> Vec<usize> {
+        self.inputs.iter().map(|(slot, _)| *slot).collect()
+    }
+    /// Evaluates the tape with the trainable leaves set to `inputs` and
+    /// returns the value of every slot; the output is the last one.
+    pub fn forward(&self, inputs: &[f32]) -> Vec<f32> {
+        assert_eq!(
+            inputs.len(),
+            self.inputs.len(),
+            "expected one value per input"
+        );
+        let mut values = vec![0.0; self.n_slots];
+        for ((slot, _), data) in self.inputs.iter().zip(inputs) {
+            values[*slot] = *data;
+        }
+        for (slot, data) in self.constants.iter() {
+            values[*slot] = *data;
+        }
+        for instr in self.instructions.iter() {
+            let x = |i: usize| values[instr.operands[i]];
+            values[instr.out] = match instr.op {
+                // Sub is an Add of a negated operand, Neg and Div are Muls
+                Operation::Add | Operation::Sub => x(0) + x(1),
+                Operation::Mul | Operation::Neg | Operation::Div => x(0) * x(1),
+                Operation::MulAdd => x(0).mul_add(x(1), x(2)),
+                Operation::Pow(power) => x(0).powf(power),
+                Operation::Powi(n) => x(0).powi(n),
+                Operation::Sqrt => x(0).powf(0.5),
+                Operation::ReLU => x(0).max(0.0),
+                Operation::ReLU6 => x(0).clamp(0.0, 6.0),
+                Operation::Exp => x(0).exp(),
+                Operation::Tanh => x(0).tanh(),
+                Operation::Sigmoid => sigmoid(x(0)),
+                Operation::Softplus => x(0).max(0.0) + (-x(0).abs()).exp().ln_1p(),
+                Operation::Sin => x(0).sin(),
+                Operation::Cos => x(0).cos(),
+                Operation::Sinh => x(0).sinh(),
+                Operation::Cosh => x(0).cosh(),
+                Operation::Max => x(0).max(x(1)),
+                Operation::Init | Operation::Const => unreachable!("leaves are not instructions"),
+            };
+        }
+        values
+    }
+    /// Gradient of the output with respect to every slot, given the slot
+    /// `values` from `forward`. Unlike `Parameter::backward` this ignores
+    /// `requires_grad`, constants get a gradient too.
+    pub fn backward(&self, values: &[f32]) -> Vec<f32> {
+        self.backward_with(values, None, f32::INFINITY)
+    }
+    /// `backward` that, given the `trainable` flag of every slot, neither
+    /// adds to nor propagates from slots that aren't trainable, and clamps
+    /// every contribution to `[-clip, clip]`, as `Parameter::backward` does.
+    fn backward_with(&self, values: &[f32], trainable: Option<&[bool]>, clip: f32) -> Vec<f32> {
+        assert_eq!(values.len(), self.n_slots, "expected one value per slot");
+        let is_trainable = |slot: usize| match trainable {
+            Some(trainable) => trainable[slot],
+            None => true,
+        };
+        let mut grads = vec![0.0; self.n_slots];
+        if let Some(last) = grads.last_mut() {
+            *last = 1.0;
+        }
+        for instr in self.instructions.iter().rev() {
+            if !is_trainable(instr.out) {
+                continue;
+            }
+            let g = grads[instr.out];
+            let out = values[instr.out];
+            let ops = &instr.operands;
+            let x = |i: usize| values[ops[i]];
+            let mut add = |slot: usize, delta: f32| {
+                if is_trainable(slot) {
+                    grads[slot] += delta.clamp(-clip, clip);
+                }
+            };
+            match instr.op {
+                Operation::Add | Operation::Sub => {
+                    add(ops[0], g);
+                    add(ops[1], g);
+                }
+                Operation::Mul | Operation::Neg | Operation::Div => {
+                    add(ops[0], x(1) * g);
+                    add(ops[1], x(0) * g);
+                }
+                Operation::MulAdd => {
+                    add(ops[0], x(1) * g);
+                    add(ops[1], x(0) * g);
+                    add(ops[2], g);
+                }
+                Operation::Max => {
+                    let winner = if x(0) >= x(1) { ops[0] } else { ops[1] };
+                    add(winner, g);
+                }
+                op => {
+                    let local_grad = match op {
+                        Operation::Pow(power) => power * x(0).powf(power - 1.0),
+                        Operation::Powi(n) => n as f32 * x(0).powi(n - 1),
+                        Operation::Sqrt => 0.5 * x(0).powf(-0.5),
+                        Operation::ReLU => (out > 0.0) as u8 as f32,
+                        Operation::ReLU6 => (x(0) > 0.0 && x(0) < 6.0) as u8 as f32,
+                        Operation::Exp => out,
+                        Operation::Tanh => 1.0 - out * out,
+                        Operation::Sigmoid => out * (1.0 - out),
+                        Operation::Softplus => sigmoid(x(0)),
+                        Operation::Sin => x(0).cos(),
+                        Operation::Cos => -x(0).sin(),
+                        Operation::Sinh => x(0).cosh(),
+                        Operation::Cosh => x(0).sinh(),
+                        _ => unreachable!("not a unary operation"),
+                    };
+                    add(ops[0], local_grad * g);
+                }
+            }
+        }
+        grads
+    }
+}
+
+/// Maximum of `v` by pairwise `max`. On backward the gradient only reaches
+/// the largest element (the first one on ties).
+pub fn reduce_max(v: &[Parameter]) -> Parameter {
+    v.iter()
+        .cloned()
+        .reduce(|acc, vi| acc.max(vi))
+        .expect("cannot take the max of an empty slice")
+}
+
+/// `a[i] + b[i]` for every element. Panics if the lengths differ.
+pub fn elementwise_add(a: &[Parameter], b: &[Parameter]) -> Vec<Parameter> {
+    assert_eq!(a.len(), b.len(), "expected slices of the same length");
+    a.iter()
+        .zip(b)
+        .map(|(ai, bi)| ai.clone() + bi.clone())
+        .collect()
+}
+
+/// `a[i] * b[i]` for every element. Panics if the lengths differ.
+pub fn elementwise_mul(a: &[Parameter], b: &[Parameter]) -> Vec<Parameter> {
+    assert_eq!(a.len(), b.len(), "expected slices of the same length");
+    a.iter()
+        .zip(b)
+        .map(|(ai, bi)| ai.clone() * bi.clone())
+        .collect()
+}
+
+/// Differentiable mean of `v`.
+pub fn batch_mean(v: &[Parameter]) -> Parameter {
+    assert!(!v.is_empty(), "cannot take the mean of an empty batch");
+    v.iter().cloned().sum::<Parameter>() / Value::from_scalar(v.len() as f32)
+}
+
+/// Differentiable standard deviation of `v`. With `unbiased` the variance is
+/// divided by `n - 1` instead of `n`. The gradient is not finite when all
+/// elements are equal.
+pub fn batch_std(v: &[Parameter], unbiased: bool) -> Parameter {
+    let n = v.len();
+    assert!(n > unbiased as usize, "not enough elements for std");
+    let mean = batch_mean(v);
+    let sum_sq: Parameter = v
+        .iter()
+        .map(|vi| (vi.clone() - mean.clone()).pow(2.0))
+        .sum();
+    let dof = if unbiased { n - 1 } else { n };
+    (sum_sq / Value::from_scalar(dof as f32)).sqrt()
+}
+
+/// Differentiable softmax of `logits`. The largest logit is subtracted
+/// before exponentiating, so large logits don't overflow.
+pub fn softmax(logits: &[Parameter]) -> Vec<Parameter> {
+    let max = logits
+        .iter()
+        .map(|l| l.data())
+        .fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<Parameter> = logits
+        .iter()
+        .map(|l| (l.clone() - Parameter::constant(max)).exp())
+        .collect();
+    let total: Parameter = exps.iter().cloned().sum();
+    exps.into_iter().map(|e| e / total.clone()).collect()
+}
+
+/// `softmax` of every row of a batch of logits. Rows don't share any nodes,
+/// so gradients never leak between samples.
+pub fn softmax_batch(logits: &[Vec<Parameter>]) -> Vec<Vec<Parameter>> {
+    logits.iter().map(|row| softmax(row)).collect()
+}
+
 #[cfg(test)]
 mod tests {
 
     use assert_approx_eq::assert_approx_eq;
 
     use super::*;
+    use std::iter::zip;
+
+    /// Central difference estimate of the derivative of `f` at `x`.
+    fn numeric_grad(f: impl Fn(f32) -> f32, x: f32) -> f32 {
+        let h = 1e-3;
+        (f(x + h) - f(x - h)) / (2.0 * h)
+    }
+
+    /// Checks value and gradient of a unary op against `f` at `x`.
+    fn check_unary(op: impl Fn(Parameter) -> Parameter, f: impl Fn(f32) -> f32, x: f32) {
+        let input = Value::from_scalar(x);
+        let out = op(input.clone());
+        out.backward();
+        assert_approx_eq!(out.data(), f(x), 1e-5);
+        assert_approx_eq!(input.grad(), numeric_grad(f, x), 1e-2);
+    }
 
     #[test]
     fn test_scalar() {
@@ -322,8 +1353,8 @@ mod tests {
         assert_eq!(x.0.lock().unwrap().grad, 46.0);
     }
 
-    #[test]
-    fn test_more_ops() {
+    /// The micrograd README example: inputs `a` and `b` and output `g`.
+    fn more_ops_graph() -> (Parameter, Parameter, Parameter) {
         let a = Value::from_scalar(-4.0);
         let b = Value::from_scalar(2.0);
         let c = a.clone() + b.clone();
@@ -336,6 +1367,13 @@ mod tests {
         let f = e.pow(2.0);
         let g = f.clone() / Value::from_scalar(2.0);
         let g = g + (Value::from_scalar(10.0) / f.clone());
+        (a, b, g)
+    }
+
+    #[test]
+    #[allow(clippy::excessive_precision)]
+    fn test_more_ops() {
+        let (a, b, g) = more_ops_graph();
         g.backward();
         let (amg, bmg, gmg) = (a, b, g);
 
@@ -346,4 +1384,721 @@ mod tests {
         assert_approx_eq!(amg.0.lock().unwrap().grad, 138.8338192420, 1e-6);
         assert_approx_eq!(bmg.0.lock().unwrap().grad, 645.5772594752, 1e-6);
     }
+
+    #[test]
+    fn test_ref_ops() {
+        let p = Value::from_scalar(3.0);
+        let a = p.relu_ref();
+        let b = p.pow_ref(2.0);
+        let c = p.clone() * Value::from_scalar(2.0);
+        let y = a.clone() + b.clone() + c.clone();
+        y.backward();
+
+        assert_eq!(y.data(), 3.0 + 9.0 + 6.0);
+        assert_eq!(a.0.lock().unwrap().grad, 1.0);
+        assert_eq!(b.0.lock().unwrap().grad, 1.0);
+        assert_eq!(c.0.lock().unwrap().grad, 1.0);
+        // d/dp (relu(p) + p^2 + 2p) = 1 + 2p + 2
+        assert_eq!(p.0.lock().unwrap().grad, 9.0);
+    }
+
+    #[test]
+    fn test_try_from_scalar() {
+        assert!(Parameter::try_from_scalar(f32::NAN).is_none());
+        assert!(Parameter::try_from_scalar(f32::INFINITY).is_none());
+        assert!(Parameter::try_from_scalar(f32::NEG_INFINITY).is_none());
+        let x = Parameter::try_from_scalar(1.5).unwrap();
+        assert_eq!(x.data(), 1.5);
+        assert!(Parameter::try_from_scalar(0.0).is_some());
+    }
+
+    #[test]
+    fn test_checked_pow() {
+        assert!(Value::from_scalar(-2.0).checked_pow(0.5).is_none());
+        assert!(Value::from_scalar(0.0).checked_pow(-1.0).is_none());
+        let x = Value::from_scalar(-2.0).checked_pow(2.0).unwrap();
+        assert_eq!(x.data(), 4.0);
+
+        // unchecked pow surfaces the NaN through is_finite
+        let x = Value::from_scalar(-2.0);
+        let y = x.clone().pow(0.5);
+        assert!(!y.is_finite());
+        y.backward();
+        assert!(!x.is_finite());
+        assert!(Value::from_scalar(1.0).is_finite());
+    }
+
+    #[test]
+    fn test_sum_product() {
+        let params: Vec<Parameter> = (1..=5).map(|i| Value::from_scalar(i as f32)).collect();
+        let total: Parameter = params.clone().into_iter().sum();
+        total.backward();
+        assert_eq!(total.data(), 15.0);
+        assert!(params.iter().all(|p| p.grad() == 1.0));
+
+        let params: Vec<Parameter> = (1..=3).map(|i| Value::from_scalar(i as f32)).collect();
+        let product: Parameter = params.clone().into_iter().product();
+        product.backward();
+        assert_eq!(product.data(), 6.0);
+        assert_eq!(params[0].grad(), 6.0);
+        assert_eq!(params[1].grad(), 3.0);
+        assert_eq!(params[2].grad(), 2.0);
+        // the starting value is a constant, not an extra input
+        assert_eq!(to_tape(&product).n_inputs(), 3);
+
+        let constants = || (1..=3).map(|i| Parameter::constant(i as f32));
+        let total: Parameter = constants().sum();
+        let product: Parameter = constants().product();
+        assert!(!total.requires_grad() && !product.requires_grad());
+    }
+
+    #[test]
+    fn test_batch_stats() {
+        let v: Vec<Parameter> = [1.0, 2.0, 3.0, 4.0]
+            .into_iter()
+            .map(Value::from_scalar)
+            .collect();
+        let mean = batch_mean(&v);
+        assert_eq!(mean.data(), 2.5);
+        mean.backward();
+        assert!(v.iter().all(|vi| vi.grad() == 0.25));
+
+        v.iter().for_each(|vi| vi.clone().zero_grad());
+        let std = batch_std(&v, false);
+        assert_approx_eq!(std.data(), 1.25f32.sqrt());
+        assert_approx_eq!(batch_std(&v, true).data(), (5.0f32 / 3.0).sqrt());
+        std.backward();
+        // d std / d v_i = (v_i - mean) / (n * std)
+        for vi in v.iter() {
+            assert_approx_eq!(vi.grad(), (vi.data() - 2.5) / (4.0 * std.data()));
+        }
+    }
+
+    #[test]
+    fn test_from_shared() {
+        let shared = Arc::new(Mutex::new(2.0));
+        let mut x = Parameter::from_shared(Arc::clone(&shared));
+        let y = x.clone() * Value::from_scalar(3.0);
+        assert_eq!(y.data(), 6.0);
+
+        *shared.lock().unwrap() = 5.0;
+        assert_eq!(x.data(), 5.0);
+        let y = x.clone() * Value::from_scalar(3.0);
+        assert_eq!(y.data(), 15.0);
+
+        // gradient steps write back into the external buffer
+        y.backward();
+        x.lr_step(0.1);
+        assert_approx_eq!(*shared.lock().unwrap(), 4.7);
+    }
+
+    #[test]
+    fn test_trig_ops() {
+        for x in [-1.3, 0.4, 2.0] {
+            check_unary(Parameter::sin, f32::sin, x);
+            check_unary(Parameter::cos, f32::cos, x);
+            check_unary(Parameter::sinh, f32::sinh, x);
+            check_unary(Parameter::cosh, f32::cosh, x);
+        }
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let x = Value::from_scalar(2.0);
+        let x_ref = x.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = x_ref.0.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(x.0.is_poisoned());
+
+        let y = Value::from_scalar(3.0);
+        let z = x.clone() * y.clone() + x.clone();
+        z.backward();
+        assert_eq!(z.data(), 8.0);
+        assert_eq!(x.grad(), 4.0);
+        assert!(x != y);
+        assert!(x == x.clone());
+    }
+
+    #[test]
+    fn test_map_data() {
+        let w = Value::from_scalar(0.2345);
+        w.map_data(|d| (d * 10.0).round() / 10.0);
+        assert_eq!(w.data(), 0.2);
+        w.map_data(|d| -d);
+        assert_eq!(w.data(), -0.2);
+    }
+
+    #[test]
+    fn test_powi() {
+        for x in [-1.5, 0.5, 3.0] {
+            let (a, b) = (Value::from_scalar(x), Value::from_scalar(x));
+            let (pa, pb) = (a.clone().powi(2), b.clone().pow(2.0));
+            pa.backward();
+            pb.backward();
+            assert_approx_eq!(pa.data(), pb.data());
+            assert_approx_eq!(a.grad(), b.grad());
+        }
+        check_unary(|p| p.powi(3), |x| x.powi(3), 1.3);
+        check_unary(|p| p.powi(-1), |x| x.powi(-1), 2.0);
+    }
+
+    #[test]
+    fn test_activations_at_extremes() {
+        let ops: [fn(Parameter) -> Parameter; 4] = [
+            Parameter::tanh,
+            Parameter::sigmoid,
+            Parameter::exp,
+            Parameter::relu,
+        ];
+        for op in ops {
+            for x in [-50.0, 50.0] {
+                let input = Value::from_scalar(x);
+                let out = op(input.clone());
+                out.backward();
+                assert!(out.is_finite());
+                assert!(input.is_finite());
+            }
+        }
+
+        let sigmoid_low = Value::from_scalar(-50.0).sigmoid();
+        assert_approx_eq!(sigmoid_low.data(), 0.0);
+        assert_approx_eq!(Value::from_scalar(50.0).sigmoid().data(), 1.0);
+        assert_approx_eq!(Value::from_scalar(50.0).tanh().data(), 1.0);
+        assert_approx_eq!(Value::from_scalar(-50.0).tanh().data(), -1.0);
+
+        // pytorch: sigmoid(0.5) = 0.6225, tanh(0.5) = 0.4621
+        check_unary(Parameter::sigmoid, |x| 1.0 / (1.0 + (-x).exp()), 0.5);
+        check_unary(Parameter::tanh, f32::tanh, 0.5);
+        check_unary(Parameter::exp, f32::exp, 0.5);
+        assert_approx_eq!(Value::from_scalar(0.5).sigmoid().data(), 0.6225, 1e-4);
+        assert_approx_eq!(Value::from_scalar(0.5).tanh().data(), 0.4621, 1e-4);
+    }
+
+    #[test]
+    fn test_relu6() {
+        for (x, out, grad) in [(-1.0, 0.0, 0.0), (3.0, 3.0, 1.0), (8.0, 6.0, 0.0)] {
+            let input = Value::from_scalar(x);
+            let y = input.clone().relu6();
+            y.backward();
+            assert_eq!(y.data(), out);
+            assert_eq!(input.grad(), grad);
+        }
+    }
+
+    #[test]
+    fn test_backward_multi() {
+        let build = || {
+            let (a, b) = (Value::from_scalar(1.5), Value::from_scalar(-2.0));
+            let shared = a.clone() * b.clone();
+            let l1 = shared.clone() + a.clone();
+            let l2 = shared.pow(2.0) * b.clone();
+            (a, b, l1, l2)
+        };
+        let (a, b, l1, l2) = build();
+        backward_multi(&[l1, l2]);
+
+        let (a_sum, b_sum, l1, l2) = build();
+        (l1 + l2).backward();
+        assert_approx_eq!(a.grad(), a_sum.grad());
+        assert_approx_eq!(b.grad(), b_sum.grad());
+    }
+
+    #[cfg(feature = "threads")]
+    #[test]
+    fn test_backward_parallel() {
+        let build = || {
+            let leaves: Vec<Parameter> = (1..=6)
+                .map(|i| Value::from_scalar(i as f32 * 0.5))
+                .collect();
+            let branches: Vec<Parameter> = leaves
+                .windows(2)
+                .map(|w| (w[0].clone() * w[1].clone()).relu() + w[0].clone().pow(2.0))
+                .collect();
+            let root = branches.iter().cloned().product::<Parameter>() + branches[0].clone();
+            (leaves, root)
+        };
+        let (leaves, root) = build();
+        root.backward();
+        let (parallel_leaves, parallel_root) = build();
+        parallel_root.backward_parallel();
+        for (l, p) in zip(leaves, parallel_leaves) {
+            assert_approx_eq!(l.grad(), p.grad(), l.grad().abs() * 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_approx_eq() {
+        let a = Value::from_scalar(1.0);
+        let b = Value::from_scalar(1.0);
+        assert!(a.approx_eq(&b, 1e-6));
+        assert!(a != b);
+        assert!(a.approx_eq(&Value::from_scalar(1.05), 0.1));
+        assert!(!a.approx_eq(&Value::from_scalar(1.5), 0.1));
+    }
+
+    #[test]
+    fn test_to_dot_constants() {
+        let w = Value::from_scalar(2.0);
+        let c = Parameter::constant(3.0);
+        let y = w.clone() * c.clone();
+        let dot = to_dot(&y);
+
+        let label = |p: &Parameter| {
+            let hash = p.0.lock().unwrap().hash.to_string();
+            dot.lines()
+                .find(|line| line.contains(&hash) && line.contains("label"))
+                .unwrap()
+                .to_string()
+        };
+        assert!(label(&w).contains("Init | data 2.0000"));
+        assert!(label(&c).contains("Const | data 3.0000"));
+        assert!(label(&y).contains("Mul | data 6.0000"));
+        assert_eq!(dot.matches("->").count(), 2);
+
+        // constants are not updated
+        y.backward();
+        let mut c_step = c.clone();
+        c_step.lr_step(0.1);
+        assert_eq!(c.data(), 3.0);
+    }
+
+    #[test]
+    fn test_mul_add() {
+        let build = || {
+            (
+                Value::from_scalar(1.5),
+                Value::from_scalar(-2.0),
+                Value::from_scalar(0.5),
+            )
+        };
+        let (a, b, c) = build();
+        let fused = a.clone().mul_add(b.clone(), c.clone()).pow(2.0);
+        fused.backward();
+        let (ua, ub, uc) = build();
+        let unfused = (ua.clone() * ub.clone() + uc.clone()).pow(2.0);
+        unfused.backward();
+
+        assert_approx_eq!(fused.data(), unfused.data());
+        assert_approx_eq!(a.grad(), ua.grad());
+        assert_approx_eq!(b.grad(), ub.grad());
+        assert_approx_eq!(c.grad(), uc.grad());
+
+        // shared operands accumulate from both positions
+        let x = Value::from_scalar(3.0);
+        let y = x.clone().mul_add(x.clone(), x.clone());
+        y.backward();
+        assert_eq!(y.data(), 12.0);
+        assert_eq!(x.grad(), 7.0);
+    }
+
+    #[test]
+    fn test_serialize_replay() {
+        let (_, _, g) = more_ops_graph();
+        let graph = serialize_graph(&g);
+        let text = graph.to_string();
+        let parsed = SerializedGraph::parse(&text).unwrap();
+        assert_eq!(parsed, graph);
+
+        g.backward();
+        let replayed = replay(&parsed);
+        let root = replayed.last().unwrap();
+        root.backward();
+        assert_eq!(root.data(), g.data());
+        assert_eq!(root.op(), Operation::Add);
+
+        let mut topo = vec![];
+        build_topo(g.clone(), &mut topo, &mut HashSet::new());
+        for (original, copy) in zip(topo, replayed.iter()) {
+            assert_eq!(original.op(), copy.op());
+            assert_approx_eq!(original.data(), copy.data());
+            assert_approx_eq!(original.grad(), copy.grad());
+        }
+        assert!(SerializedGraph::parse("0 Foo 1.0 -").is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_structure() {
+        assert!(SerializedGraph::parse("0 Init 1 -\n1 Init 2 -\n2 Add 3 0,1").is_ok());
+        let errors = [
+            ("1 Init 1 -", "expected id 0 but found 1"),
+            ("0 Init 1 -\n0 Init 2 -", "expected id 1 but found 0"),
+            (
+                "0 Init 1 -\n1 Add 2 0,5",
+                "node 1 refers to node 5, which doesn't come before it",
+            ),
+            (
+                "0 Init 1 -\n1 Exp 2 1",
+                "node 1 refers to node 1, which doesn't come before it",
+            ),
+            (
+                "0 Init 1 -\n1 Add 2 0",
+                "node 1 has 1 operands but Add takes 2",
+            ),
+            (
+                "0 Init 1 0",
+                "node 0 refers to node 0, which doesn't come before it",
+            ),
+            (
+                "0 Init 1 -\n1 Init 2 -\n2 Tanh 3 0,1",
+                "node 2 has 2 operands but Tanh takes 1",
+            ),
+        ];
+        for (text, error) in errors {
+            assert_eq!(SerializedGraph::parse(text), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_softplus() {
+        for x in [-3.0, -0.5, 0.0, 0.7, 2.0] {
+            check_unary(Parameter::softplus, |x: f32| x.exp().ln_1p(), x);
+            let input = Value::from_scalar(x);
+            let out = input.clone().softplus();
+            out.backward();
+            assert!(out.data() > 0.0);
+            assert_approx_eq!(input.grad(), Value::from_scalar(x).sigmoid().data());
+        }
+        // close to relu away from zero and no overflow for large inputs
+        assert_approx_eq!(Value::from_scalar(100.0).softplus().data(), 100.0);
+        assert_approx_eq!(Value::from_scalar(-20.0).softplus().data(), 0.0);
+        assert!(Value::from_scalar(1000.0).softplus().is_finite());
+        assert!(Value::from_scalar(-1000.0).softplus().data() >= 0.0);
+    }
+
+    #[test]
+    fn test_partially_frozen_graph() {
+        let w = Value::from_scalar(3.0);
+        let frozen_a = Value::from_scalar(2.0);
+        let frozen_b = Value::from_scalar(-1.5);
+        frozen_a.set_requires_grad(false);
+        frozen_b.set_requires_grad(false);
+        assert!(!frozen_a.requires_grad());
+
+        // frozen_part only depends on frozen leaves and is skipped entirely
+        let frozen_part = (frozen_a.clone() * frozen_b.clone()).exp();
+        assert!(!frozen_part.requires_grad());
+        let out = w.clone() * frozen_a.clone() + frozen_part.clone();
+        assert!(out.requires_grad());
+        out.backward();
+
+        assert_eq!(frozen_a.grad(), 0.0);
+        assert_eq!(frozen_b.grad(), 0.0);
+        assert_eq!(frozen_part.grad(), 0.0);
+        assert_eq!(w.grad(), 2.0);
+    }
+
+    #[test]
+    fn test_softmax_batch() {
+        let logits: Vec<Vec<Parameter>> = [[1.0, 2.0, 3.0], [1000.0, 0.0, -1000.0]]
+            .iter()
+            .map(|row| row.iter().map(|l| Value::from_scalar(*l)).collect())
+            .collect();
+        let probs = softmax_batch(&logits);
+        for row in probs.iter() {
+            assert!(row.iter().all(|p| p.is_finite()));
+            assert_approx_eq!(row.iter().map(|p| p.data()).sum::<f32>(), 1.0);
+        }
+        assert_approx_eq!(probs[1][0].data(), 1.0);
+
+        probs[0][2].backward();
+        let p = probs[0][2].data();
+        // d p_2 / d l_j = p_2 * (delta_2j - p_j)
+        for (j, logit) in logits[0].iter().enumerate() {
+            let delta = if j == 2 { 1.0 } else { 0.0 };
+            assert_approx_eq!(logit.grad(), p * (delta - probs[0][j].data()));
+        }
+        assert!(logits[1].iter().all(|l| l.grad() == 0.0));
+    }
+
+    #[test]
+    fn test_is_leaf() {
+        let a = Value::from_scalar(1.0);
+        let b = Parameter::constant(2.0);
+        assert!(a.is_leaf());
+        assert!(b.is_leaf());
+        assert!(!(a.clone() + b).is_leaf());
+        assert!(!a.relu().is_leaf());
+    }
+
+    #[test]
+    fn test_backward_for() {
+        let target = Value::from_scalar(3.0);
+        let x = Value::from_scalar(-2.0);
+        let a = Value::from_scalar(0.5);
+        let b = Value::from_scalar(1.5);
+        let irrelevant = (a.clone() * b.clone()).exp();
+        let out = (target.clone() * x.clone()).tanh() + irrelevant;
+        out.backward_for(std::slice::from_ref(&target));
+
+        assert_approx_eq!(target.grad(), x.data() * (1.0 - (-6.0f32).tanh().powi(2)));
+        assert_eq!(a.grad(), 0.0);
+        assert_eq!(b.grad(), 0.0);
+    }
+
+    #[test]
+    fn test_gt_mask() {
+        let a = Value::from_scalar(2.0);
+        let b = Value::from_scalar(1.0);
+        assert_eq!(a.gt(&b).data(), 1.0);
+        assert_eq!(b.gt(&a).data(), 0.0);
+        assert_eq!(a.gt(&a).data(), 0.0);
+
+        let x = Value::from_scalar(5.0);
+        let out = x.clone() * a.gt(&b) + x.clone().pow(2.0) * b.gt(&a);
+        out.backward();
+        assert_eq!(out.data(), 5.0);
+        assert_eq!(x.grad(), 1.0);
+        assert_eq!(a.grad(), 0.0);
+        assert_eq!(b.grad(), 0.0);
+    }
+
+    #[test]
+    #[allow(clippy::excessive_precision)]
+    fn test_tape() {
+        let (a, b, g) = more_ops_graph();
+        let tape = to_tape(&g);
+        let values = tape.forward(&tape.recorded_inputs());
+        assert_approx_eq!(*values.last().unwrap(), 24.7040816327, 1e-4);
+        let grads = tape.backward(&values);
+
+        g.backward();
+        let mut topo = vec![];
+        build_topo(g.clone(), &mut topo, &mut HashSet::new());
+        assert_eq!(values.len(), topo.len());
+        for (node, (value, grad)) in zip(topo.iter(), zip(values.iter(), grads.iter())) {
+            assert_approx_eq!(node.data(), value);
+            // the tape also gives constants, such as Neg's -1, a gradient
+            if node.requires_grad() {
+                assert_approx_eq!(node.grad(), grad, 1e-3);
+            }
+        }
+        let slot_of = |p: &Parameter| topo.iter().position(|node| node == p).unwrap();
+        assert_approx_eq!(grads[slot_of(&a)], 138.8338192420, 1e-3);
+        assert_approx_eq!(grads[slot_of(&b)], 645.5772594752, 1e-3);
+    }
+
+    #[test]
+    fn test_reduce_max() {
+        let v: Vec<Parameter> = [0.5, -1.0, 3.0, 2.0, 3.0]
+            .iter()
+            .map(|x| Value::from_scalar(*x))
+            .collect();
+        let out = reduce_max(&v) * Value::from_scalar(2.0);
+        out.backward();
+        assert_eq!(out.data(), 6.0);
+        let grads: Vec<f32> = v.iter().map(|vi| vi.grad()).collect();
+        assert_eq!(grads, vec![0.0, 0.0, 2.0, 0.0, 0.0]);
+
+        // the tape and replay agree with the graph
+        let tape = to_tape(&out);
+        assert_eq!(*tape.forward(&tape.recorded_inputs()).last().unwrap(), 6.0);
+        let replayed = replay(&serialize_graph(&out));
+        assert_eq!(replayed.last().unwrap().data(), 6.0);
+    }
+
+    #[test]
+    fn test_accumulate_grad() {
+        // custom op out = x^3 with a hand written backward pass
+        let x = Value::from_scalar(2.0);
+        let out = Parameter::from_scalar(x.data().powi(3));
+        out.accumulate_grad(1.0);
+        x.accumulate_grad(3.0 * x.data().powi(2) * out.grad());
+        x.accumulate_grad(0.5);
+        assert_eq!(x.grad(), 12.5);
+
+        let frozen = Value::from_scalar(1.0);
+        frozen.set_requires_grad(false);
+        frozen.accumulate_grad(1.0);
+        assert_eq!(frozen.grad(), 0.0);
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let x = Value::from_scalar(1.5);
+        let relu = x.clone().relu();
+        let scale = Parameter::constant(2.0) * Parameter::constant(3.0);
+        let out = x.clone() * scale + relu.clone();
+        let folded = fold_constants(&out);
+        assert_eq!(folded.data(), out.data());
+
+        let graph = serialize_graph(&folded);
+        let consts: Vec<f32> = graph
+            .nodes
+            .iter()
+            .filter(|node| node.op == Operation::Const)
+            .map(|node| node.data)
+            .collect();
+        assert_eq!(consts, vec![6.0]);
+        // x, 6, x * 6, relu, sum
+        assert_eq!(graph.nodes.len(), 5);
+
+        // the trainable subgraph is reused as is
+        let mut topo = vec![];
+        build_topo(folded.clone(), &mut topo, &mut HashSet::new());
+        assert!(topo.contains(&relu));
+        assert!(topo.contains(&x));
+
+        folded.backward();
+        assert_eq!(x.grad(), 7.0);
+
+        // x * (5 - 1): x, 5, 1, -1, -1 * 1, 5 + -1 and the product
+        let diff = x.clone() * (Parameter::constant(5.0) - Parameter::constant(1.0));
+        assert_eq!(serialize_graph(&diff).nodes.len(), 7);
+        let graph = serialize_graph(&fold_constants(&diff));
+        assert_eq!(graph.nodes.len(), 3);
+        assert!(graph
+            .nodes
+            .iter()
+            .any(|node| node.data == 4.0 && node.op == Operation::Const));
+    }
+
+    #[test]
+    fn test_backward_fresh() {
+        let graph = || {
+            let a = Value::from_scalar(-1.5);
+            let b = Value::from_scalar(0.5);
+            let frozen = Value::from_scalar(2.0);
+            frozen.set_requires_grad(false);
+            let out = (a.clone() * b.clone()).tanh()
+                + (b.clone() - a.clone()).relu() * frozen.clone()
+                + (b.clone() / a.clone()).exp();
+            (out, vec![a, b, frozen])
+        };
+        let (out, leaves) = graph();
+        out.backward_fresh();
+        let first: Vec<f32> = leaves.iter().map(|l| l.grad()).collect();
+        out.backward_fresh();
+        let second: Vec<f32> = leaves.iter().map(|l| l.grad()).collect();
+        assert_eq!(first, second);
+
+        let (out, leaves) = graph();
+        out.backward();
+        for (fresh, expected) in zip(first, leaves.iter().map(|l| l.grad())) {
+            assert_approx_eq!(fresh, expected, 1e-6);
+        }
+        assert_eq!(leaves[2].grad(), 0.0);
+    }
+
+    #[test]
+    fn test_backward_fresh_frozen_interior() {
+        let graph = || {
+            let a = Value::from_scalar(1.5);
+            let b = Value::from_scalar(-0.5);
+            let hidden = (a.clone() * b.clone()).tanh();
+            hidden.set_requires_grad(false);
+            let out = hidden.clone() * a.clone() + b.clone().exp();
+            (out, hidden, vec![a, b])
+        };
+        let (out, hidden, leaves) = graph();
+        out.backward_fresh();
+        let fresh: Vec<f32> = leaves.iter().map(|l| l.grad()).collect();
+        assert_eq!(hidden.grad(), 0.0);
+
+        let (out, _, leaves) = graph();
+        out.backward();
+        let expected: Vec<f32> = leaves.iter().map(|l| l.grad()).collect();
+        // only the direct paths count, nothing flows through the frozen node
+        assert_approx_eq!(expected[0], (1.5f32 * -0.5).tanh());
+        assert_approx_eq!(expected[1], (-0.5f32).exp());
+        for (fresh, expected) in zip(fresh, expected) {
+            assert_approx_eq!(fresh, expected, 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_elementwise_ops() {
+        let a: Vec<Parameter> = [1.0, -2.0, 3.0]
+            .iter()
+            .map(|x| Value::from_scalar(*x))
+            .collect();
+        let b: Vec<Parameter> = [4.0, 5.0, -6.0]
+            .iter()
+            .map(|x| Value::from_scalar(*x))
+            .collect();
+        let sum = elementwise_add(&a, &b);
+        assert_eq!(
+            sum.iter().map(|p| p.data()).collect::<Vec<f32>>(),
+            vec![5.0, 3.0, -3.0]
+        );
+        let product = elementwise_mul(&a, &b);
+        assert_eq!(
+            product.iter().map(|p| p.data()).collect::<Vec<f32>>(),
+            vec![4.0, -10.0, -18.0]
+        );
+
+        // d/da_i sum(a + b) + sum(a * b) = 1 + b_i
+        let out: Parameter = sum.into_iter().chain(product).sum();
+        out.backward();
+        for (ai, bi) in zip(&a, &b) {
+            assert_eq!(ai.grad(), 1.0 + bi.data());
+            assert_eq!(bi.grad(), 1.0 + ai.data());
+        }
+    }
+
+    #[test]
+    fn test_take_grad_fn() {
+        let build = || {
+            let x = Parameter::from_scalar(1.5);
+            let out = (x.clone() * Parameter::from_scalar(2.0)).relu();
+            (x, out)
+        };
+        let (x, out) = build();
+        out.backward();
+
+        let (x_manual, out_manual) = build();
+        let relu_fn = out_manual.take_grad_fn().unwrap();
+        assert!(out_manual.take_grad_fn().is_none());
+        let product = lock(&out_manual.0).previous[0].clone();
+        let mul_fn = product.take_grad_fn().unwrap();
+        out_manual.map_grad(|_| 1.0);
+        relu_fn.call();
+        mul_fn.call();
+        assert_eq!(x_manual.grad(), x.grad());
+        assert_eq!(x_manual.grad(), 2.0);
+
+        assert!(x_manual.take_grad_fn().is_none());
+        let frozen = Parameter::constant(3.0).relu();
+        assert!(frozen.take_grad_fn().is_none());
+    }
+
+    #[test]
+    fn test_constant_nodes_skip_closure() {
+        let c = |x: f32| Parameter::constant(x);
+        let scale = (c(2.0) * c(3.0) + c(1.0)).exp().pow(0.5);
+        let shift = c(0.5).tanh().max(c(0.25));
+        let w = Parameter::from_scalar(1.5);
+        let out = w.clone() * scale.clone() + shift.clone();
+
+        assert!(!scale.requires_grad() && !scale.has_grad_fn());
+        assert!(!shift.has_grad_fn());
+        assert!(!c(1.0).has_grad_fn() && !w.has_grad_fn());
+        assert!(out.has_grad_fn());
+
+        let diff = c(5.0) - c(1.0);
+        let neg = -c(2.0);
+        assert!(!diff.requires_grad() && !diff.has_grad_fn());
+        assert!(!neg.requires_grad() && !neg.has_grad_fn());
+        assert!((-w.clone()).has_grad_fn());
+
+        out.backward();
+        assert!(!out.has_grad_fn());
+        assert_approx_eq!(w.grad(), scale.data());
+        assert_eq!(scale.grad(), 0.0);
+    }
+
+    #[test]
+    fn test_with_grad_pow_backward() {
+        let x = Parameter::with_grad(3.0, 0.5);
+        assert_eq!((x.data(), x.grad()), (3.0, 0.5));
+
+        let out = x.clone().pow(2.5);
+        out.map_grad(|_| 2.0);
+        out.take_grad_fn().unwrap().call();
+        // d/dx x^2.5 = 2.5 * x^1.5, scaled by the seeded output gradient
+        assert_approx_eq!(x.grad(), 0.5 + 2.0 * 2.5 * 3f32.powf(1.5), 1e-4);
+    }
 }
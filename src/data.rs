@@ -0,0 +1,144 @@
+use std::{fs, path::Path};
+
+use crate::math::make_moons_seeded;
+
+/// A labelled dataset that can be indexed sample by sample.
+pub trait Dataset {
+    /// Number of samples.
+    fn len(&self) -> usize;
+    /// Features and label of sample `i`.
+    fn get(&self, i: usize) -> (Vec<f32>, f32);
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The two moons toy dataset with labels -1.0 and 1.0, as expected by `loss`.
+pub struct MoonsDataset {
+    x: Vec<(f32, f32)>,
+    y: Vec<f32>,
+}
+
+impl MoonsDataset {
+    /// `n` shuffled points per moon with gaussian `noise`, see `make_moons_seeded`.
+    pub fn new(n: usize, noise: f32, seed: u64) -> MoonsDataset {
+        let (x, y01) = make_moons_seeded(n, true, noise, seed);
+        MoonsDataset {
+            x,
+            y: y01.iter().map(|yi| yi * 2.0 - 1.0).collect(),
+        }
+    }
+}
+
+impl Dataset for MoonsDataset {
+    fn len(&self) -> usize {
+        self.y.len()
+    }
+    fn get(&self, i: usize) -> (Vec<f32>, f32) {
+        let (x1, x2) = self.x[i];
+        (vec![x1, x2], self.y[i])
+    }
+}
+
+/// Numeric comma separated data, one sample per line with the label in the
+/// last column. A first line that doesn't parse is treated as a header.
+pub struct CsvDataset {
+    x: Vec<Vec<f32>>,
+    y: Vec<f32>,
+}
+
+impl CsvDataset {
+    /// Reads the dataset from the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<CsvDataset, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        CsvDataset::parse(&text)
+    }
+    /// Reads the dataset from csv text.
+    pub fn parse(text: &str) -> Result<CsvDataset, String> {
+        let mut x = vec![];
+        let mut y = vec![];
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let row: Result<Vec<f32>, _> = line.split(',').map(|v| v.trim().parse()).collect();
+            let mut row = match row {
+                Ok(row) => row,
+                Err(_) if i == 0 => continue,
+                Err(_) => return Err(format!("line {}: expected numbers: {}", i + 1, line)),
+            };
+            if row.len() < 2 {
+                return Err(format!("line {}: expected features and a label", i + 1));
+            }
+            if let Some(first) = x.first().map(Vec::len) {
+                if first != row.len() - 1 {
+                    return Err(format!("line {}: expected {} columns", i + 1, first + 1));
+                }
+            }
+            y.push(row.pop().unwrap());
+            x.push(row);
+        }
+        Ok(CsvDataset { x, y })
+    }
+}
+
+impl Dataset for CsvDataset {
+    fn len(&self) -> usize {
+        self.y.len()
+    }
+    fn get(&self, i: usize) -> (Vec<f32>, f32) {
+        (self.x[i].clone(), self.y[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::nn::{Activation, MlpBuilder};
+
+    /// Label is the sign of the first feature.
+    struct SignDataset;
+
+    impl Dataset for SignDataset {
+        fn len(&self) -> usize {
+            8
+        }
+        fn get(&self, i: usize) -> (Vec<f32>, f32) {
+            let x = i as f32 - 3.5;
+            (vec![x], x.signum())
+        }
+    }
+
+    #[test]
+    fn test_custom_dataset() {
+        let model = MlpBuilder::new()
+            .input(1)
+            .layer(1, Activation::Linear)
+            .seed(5)
+            .build();
+        let history = model.fit_dataset(&SignDataset, 30, 0.05);
+        assert!(history.losses.last().unwrap() < history.losses.first().unwrap());
+        assert_eq!(*history.accuracies.last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_moons_dataset() {
+        let data = MoonsDataset::new(20, 0.1, 0);
+        assert_eq!(data.len(), 40);
+        for i in 0..data.len() {
+            let (x, y) = data.get(i);
+            assert_eq!(x.len(), 2);
+            assert!(y == 1.0 || y == -1.0);
+        }
+    }
+
+    #[test]
+    fn test_csv_dataset() {
+        let data = CsvDataset::parse("x1,x2,y\n0.5, 1.0, 1\n-0.5,2.0,-1\n\n").unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data.get(1), (vec![-0.5, 2.0], -1.0));
+        assert!(CsvDataset::parse("1,2,3\n1,2\n").is_err());
+        assert!(CsvDataset::parse("1,2\nfoo,1\n").is_err());
+    }
+}
@@ -1,5 +1,14 @@
+//! Scalar autograd and small neural networks. The differentiable scalar type
+//! is `autograd::Parameter`, also available as `micrograd_rs::Parameter`.
+
 pub mod autograd;
 pub mod nn;
+pub mod optim;
+pub mod prelude;
+pub mod tensor;
 
+pub mod data;
 pub mod math;
 pub mod plotting;
+
+pub use autograd::Parameter;
@@ -1,17 +1,21 @@
 use std::{f32::consts::PI, iter::zip};
 
 use num_traits::Float;
+use rand::rngs::StdRng;
 use rand::thread_rng;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
 use rand_distr::{Distribution, Normal};
 
 /// Shuffles the given slices in place, such that the slices retain the relative
 /// order between each other. Useful for shuffling `x` and `y` for ML tasks.
 /// Credit: https://stackoverflow.com/questions/60476393/is-there-a-way-to-shuffle-two-or-more-lists-in-the-same-order
 pub fn shuffle<T: Copy>(slices: &mut [&mut [T]]) {
-    if slices.len() > 0 {
-        let mut rng = thread_rng();
+    shuffle_with_rng(slices, &mut thread_rng());
+}
 
+/// `shuffle` drawing from the given `rng`, e.g. a seeded one.
+pub fn shuffle_with_rng<T: Copy, R: Rng>(slices: &mut [&mut [T]], rng: &mut R) {
+    if !slices.is_empty() {
         let len = slices[0].len();
         assert!(slices.iter().all(|s| s.len() == len));
 
@@ -19,24 +23,96 @@ pub fn shuffle<T: Copy>(slices: &mut [&mut [T]]) {
             let next = rng.gen_range(i..len);
 
             for slice in slices.iter_mut() {
-                let tmp: T = slice[i];
-                slice[i] = slice[next];
-                slice[next] = tmp;
+                slice.swap(i, next);
             }
         }
     }
 }
 
+/// Shuffles 2d points `x` and their labels `y` in the same order, seeded
+/// by `seed`, so every point keeps its label.
+pub fn shuffle_xy(x: &mut [(f32, f32)], y: &mut [f32], seed: u64) {
+    assert_eq!(x.len(), y.len(), "expected one label per point");
+    let mut rng = StdRng::seed_from_u64(seed);
+    for i in 0..x.len() {
+        let next = rng.gen_range(i..x.len());
+        x.swap(i, next);
+        y.swap(i, next);
+    }
+}
+
+/// Standardizes each feature (column) of `x` in place to zero mean and unit
+/// variance. Returns the per-feature mean and standard deviation used, with
+/// constant features getting a standard deviation of 1.
+pub fn standardize(x: &mut [Vec<f32>]) -> (Vec<f32>, Vec<f32>) {
+    if x.is_empty() {
+        return (vec![], vec![]);
+    }
+    let n = x.len() as f32;
+    let dim = x[0].len();
+    let mean: Vec<f32> = (0..dim)
+        .map(|j| x.iter().map(|row| row[j]).sum::<f32>() / n)
+        .collect();
+    let std: Vec<f32> = (0..dim)
+        .map(|j| {
+            let var = x.iter().map(|row| (row[j] - mean[j]).powi(2)).sum::<f32>() / n;
+            if var > 0.0 {
+                var.sqrt()
+            } else {
+                1.0
+            }
+        })
+        .collect();
+    for row in x.iter_mut() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (*v - mean[j]) / std[j];
+        }
+    }
+    (mean, std)
+}
+
+/// One-hot encodes integer class labels: row `i` is all zeros except for a
+/// 1.0 at index `labels[i]`. Panics if a label is not below `num_classes`.
+pub fn one_hot(labels: &[usize], num_classes: usize) -> Vec<Vec<f32>> {
+    labels
+        .iter()
+        .map(|label| {
+            assert!(
+                *label < num_classes,
+                "label {} out of range for {} classes",
+                label,
+                num_classes
+            );
+            let mut row = vec![0.0; num_classes];
+            row[*label] = 1.0;
+            row
+        })
+        .collect()
+}
+
+/// Multiplies the first coordinate of every point by `sx` and the second by
+/// `sy`, e.g. to stretch `make_moons` output. Points may end up outside the
+/// `[-2, 2]` range `draw_chart` shows; `standardize` undoes the scaling.
+pub fn scale_dataset(x: &mut [(f32, f32)], sx: f32, sy: f32) {
+    for (x1, x2) in x.iter_mut() {
+        *x1 *= sx;
+        *x2 *= sy;
+    }
+}
+
 /// Basically Numpy linspace. Returns `n` evenly spaced elements between
-/// `l` and `h`.
+/// `l` and `h`. Like Numpy, a single element is just `l`.
 pub fn linspace<T: Float + std::convert::From<u16>>(l: T, h: T, n: usize) -> Vec<T> {
-    let size: T = (n as u16 - 1)
-        .try_into()
-        .expect("too many elements: max is 2^16");
+    if n <= 1 {
+        return vec![l; n];
+    }
+    let size: T = u16::try_from(n - 1)
+        .expect("too many elements: max is 2^16")
+        .into();
     let dx = (h - l) / size;
 
     (1..=n)
-        .scan(-dx, |a, _| {
+        .scan(l - dx, |a, _| {
             *a = *a + dx;
             Some(*a)
         })
@@ -49,6 +125,31 @@ pub fn make_moons(
     n_samples: usize,
     should_shuffle: bool,
     noise: f32,
+) -> (Vec<(f32, f32)>, Vec<f32>) {
+    make_moons_with_rng(n_samples, should_shuffle, noise, &mut thread_rng())
+}
+
+/// `make_moons` with a seeded RNG, so the same `seed` always yields the same
+/// dataset.
+pub fn make_moons_seeded(
+    n_samples: usize,
+    should_shuffle: bool,
+    noise: f32,
+    seed: u64,
+) -> (Vec<(f32, f32)>, Vec<f32>) {
+    make_moons_with_rng(
+        n_samples,
+        should_shuffle,
+        noise,
+        &mut StdRng::seed_from_u64(seed),
+    )
+}
+
+fn make_moons_with_rng<R: Rng>(
+    n_samples: usize,
+    should_shuffle: bool,
+    noise: f32,
+    rng: &mut R,
 ) -> (Vec<(f32, f32)>, Vec<f32>) {
     let n_samples_in = n_samples;
     let n_samples_out = n_samples;
@@ -70,14 +171,8 @@ pub fn make_moons(
         .map(|v| 0.5 - v.sin())
         .collect();
 
-    let concat_x: Vec<f32> = outer_circ_x
-        .into_iter()
-        .chain(inner_circ_x.into_iter())
-        .collect();
-    let concat_y: Vec<f32> = outer_circ_y
-        .into_iter()
-        .chain(inner_circ_y.into_iter())
-        .collect();
+    let concat_x: Vec<f32> = outer_circ_x.into_iter().chain(inner_circ_x).collect();
+    let concat_y: Vec<f32> = outer_circ_y.into_iter().chain(inner_circ_y).collect();
     let mut x: Vec<(f32, f32)> = zip(concat_x, concat_y).collect();
     let mut y: Vec<f32> = vec![0.0; n_samples_out]
         .into_iter()
@@ -87,7 +182,7 @@ pub fn make_moons(
     if should_shuffle {
         let mut x1: Vec<f32> = x.clone().into_iter().map(|(x1, _)| x1).collect();
         let mut x2: Vec<f32> = x.clone().into_iter().map(|(_, x2)| x2).collect();
-        shuffle(&mut [&mut x1, &mut x2, &mut y]);
+        shuffle_with_rng(&mut [&mut x1, &mut x2, &mut y], rng);
         x = zip(x1, x2).collect();
     }
 
@@ -96,13 +191,84 @@ pub fn make_moons(
         let normal = Normal::new(0.0, noise).unwrap();
         x = x
             .into_iter()
-            .map(|(x, y)| {
-                (
-                    x + normal.sample(&mut thread_rng()),
-                    y + normal.sample(&mut thread_rng()),
-                )
-            })
+            .map(|(x, y)| (x + normal.sample(rng), y + normal.sample(rng)))
             .collect();
     }
     (x, y)
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_make_moons_seeded() {
+        let (x_a, y_a) = make_moons_seeded(50, true, 0.1, 42);
+        let (x_b, y_b) = make_moons_seeded(50, true, 0.1, 42);
+        assert_eq!(x_a, x_b);
+        assert_eq!(y_a, y_b);
+
+        let (x_c, _) = make_moons_seeded(50, true, 0.1, 43);
+        assert_ne!(x_a, x_c);
+    }
+
+    #[test]
+    fn test_linspace_short() {
+        assert_eq!(linspace(0.0f32, 1.0, 0), vec![]);
+        assert_eq!(linspace(0.5f32, 1.0, 1), vec![0.5]);
+        assert_eq!(linspace(0.0f32, 1.0, 3), vec![0.0, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn test_make_moons_single_sample() {
+        let (x, y) = make_moons(1, false, 0.0);
+        assert_eq!(x.len(), 2);
+        assert!(x.iter().all(|(x1, x2)| x1.is_finite() && x2.is_finite()));
+        assert_eq!(y, vec![0.0, 1.0]);
+        assert_eq!(make_moons(0, false, 0.0), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_scale_dataset() {
+        let mut x = vec![(1.0, 2.0), (-0.5, 0.25)];
+        scale_dataset(&mut x, 2.0, 0.5);
+        assert_eq!(x, vec![(2.0, 1.0), (-1.0, 0.125)]);
+    }
+
+    #[test]
+    fn test_one_hot() {
+        let labels = [2, 0, 1, 2];
+        let encoded = one_hot(&labels, 3);
+        assert_eq!(encoded.len(), labels.len());
+        for (row, label) in zip(encoded, labels) {
+            assert_eq!(row.len(), 3);
+            assert_eq!(row.iter().filter(|v| **v == 1.0).count(), 1);
+            assert_eq!(row[label], 1.0);
+            assert_eq!(row.iter().sum::<f32>(), 1.0);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_one_hot_out_of_range() {
+        one_hot(&[0, 3], 3);
+    }
+
+    #[test]
+    fn test_shuffle_xy() {
+        let (mut x, y01) = make_moons_seeded(20, true, 0.1, 3);
+        let mut y = y01.clone();
+        let original = x.clone();
+        shuffle_xy(&mut x, &mut y, 7);
+        assert_ne!(x, original);
+        for (xi, yi) in zip(&x, &y) {
+            let i = original.iter().position(|p| p == xi).unwrap();
+            assert_eq!(*yi, y01[i]);
+        }
+
+        let (mut x2, mut y2) = (original.clone(), y01.clone());
+        shuffle_xy(&mut x2, &mut y2, 7);
+        assert_eq!(x2, x);
+    }
+}
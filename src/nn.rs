@@ -1,49 +1,142 @@
 use core::fmt;
-use rand::Rng;
-use std::{iter::zip, ops::RangeInclusive};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+#[cfg(feature = "threads")]
+use std::thread;
+use std::{fs, io, iter::zip, ops::RangeInclusive, sync::Mutex, time::Instant};
 
-use crate::autograd::Parameter;
+use crate::{
+    autograd::{sigmoid, Parameter},
+    data::Dataset,
+    math::linspace,
+};
+
+/// Nonlinearity applied to the output of a neuron.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    Linear,
+    ReLU,
+    Tanh,
+    Sigmoid,
+}
+
+impl Activation {
+    /// ReLU for `nonlinear` and Linear otherwise, as taken by the `new`
+    /// constructors.
+    fn from_nonlinear(nonlinear: bool) -> Activation {
+        if nonlinear {
+            Activation::ReLU
+        } else {
+            Activation::Linear
+        }
+    }
+    fn apply(self, x: Parameter) -> Parameter {
+        match self {
+            Activation::Linear => x,
+            Activation::ReLU => x.relu(),
+            Activation::Tanh => x.tanh(),
+            Activation::Sigmoid => x.sigmoid(),
+        }
+    }
+    fn apply_eval(self, x: f32) -> f32 {
+        match self {
+            Activation::Linear => x,
+            Activation::ReLU => x.max(0.0),
+            Activation::Tanh => x.tanh(),
+            Activation::Sigmoid => sigmoid(x),
+        }
+    }
+}
+
+/// Weight initialization scheme. Weights are drawn uniformly from
+/// `[-bound, bound]`; biases start at zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    /// `bound = 1`.
+    Uniform,
+    /// Glorot/Xavier, `bound = sqrt(6 / (in_dim + out_dim))`.
+    Xavier,
+    /// He/Kaiming, `bound = sqrt(6 / in_dim)`. Suited to ReLU layers.
+    He,
+}
+
+impl Init {
+    fn bound(self, in_dim: usize, out_dim: usize) -> f32 {
+        match self {
+            Init::Uniform => 1.0,
+            Init::Xavier => (6.0 / (in_dim + out_dim).max(1) as f32).sqrt(),
+            Init::He => (6.0 / in_dim.max(1) as f32).sqrt(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Neuron {
     weights: Vec<Parameter>,
-    bias: Parameter,
-    nonlinear: bool,
+    bias: Option<Parameter>,
+    activation: Activation,
     in_dim: usize,
 }
 
-fn uniform_sample(range: RangeInclusive<f32>) -> f32 {
-    rand::thread_rng().gen_range(range) as f32
+/// Uniform sample from `range` drawn from `rng`.
+fn uniform_sample<R: Rng>(range: RangeInclusive<f32>, rng: &mut R) -> f32 {
+    rng.gen_range(range)
 }
 
 impl Neuron {
-    pub fn new(in_dim: usize, nonlinear: bool) -> Neuron {
+    /// Neuron with uniformly initialized weights. With `bias == false` the
+    /// neuron has no bias parameter at all.
+    pub fn new(in_dim: usize, nonlinear: bool, bias: bool) -> Neuron {
+        let activation = Activation::from_nonlinear(nonlinear);
+        Neuron::with_rng(in_dim, activation, bias, 1.0, &mut rand::thread_rng())
+    }
+    /// Neuron with weights drawn from `rng` in `[-bound, bound]`.
+    fn with_rng(
+        in_dim: usize,
+        activation: Activation,
+        bias: bool,
+        bound: f32,
+        rng: &mut impl Rng,
+    ) -> Neuron {
         let weights = (0..in_dim)
-            .map(|_| Parameter::from_scalar(uniform_sample(-1.0..=1.0)))
+            .map(|_| Parameter::from_scalar(uniform_sample(-bound..=bound, rng)))
             .collect();
-        let bias = Parameter::from_scalar(0.0);
+        let bias = bias.then(|| Parameter::from_scalar(0.0));
         Neuron {
             weights,
             bias,
-            nonlinear,
+            activation,
             in_dim,
         }
     }
+    /// Weights in input order followed by the bias, if any.
     fn parameters(&self) -> Vec<Parameter> {
         let mut params = self.weights.clone();
-        params.push(self.bias.clone());
+        params.extend(self.bias.clone());
         params
     }
     // Shape
     // weights: (2,) x: (2,)
     pub fn forward(&self, x: Vec<Parameter>) -> Parameter {
-        let act =
-            zip(self.weights.clone(), x).fold(self.bias.clone(), |sum, (wi, xi)| sum + (wi * xi));
-        if self.nonlinear {
-            act.relu()
-        } else {
-            act
-        }
+        let act = zip(self.weights.clone(), x)
+            .fold(self.bias_or_zero(), |sum, (wi, xi)| wi.mul_add(xi, sum));
+        self.activation.apply(act)
+    }
+    /// Forward pass on raw values without building a graph.
+    pub fn forward_eval(&self, x: &[f32]) -> f32 {
+        let act = zip(self.weights.iter(), x)
+            .fold(self.bias_data(), |sum, (wi, xi)| sum + wi.data() * xi);
+        self.activate(act)
+    }
+    fn bias_or_zero(&self) -> Parameter {
+        self.bias
+            .clone()
+            .unwrap_or_else(|| Parameter::from_scalar(0.0))
+    }
+    fn bias_data(&self) -> f32 {
+        self.bias.as_ref().map_or(0.0, |bias| bias.data())
+    }
+    fn activate(&self, act: f32) -> f32 {
+        self.activation.apply_eval(act)
     }
 }
 
@@ -58,44 +151,381 @@ impl fmt::Debug for Neuron {
 
 pub struct Layer {
     neurons: Vec<Neuron>,
+    /// Scheme used by `MLP::reset_parameters`.
+    init: Init,
 }
 
 impl Layer {
-    pub fn new(in_dim: usize, out_dim: usize, nonlinear: bool) -> Layer {
+    pub fn new(in_dim: usize, out_dim: usize, nonlinear: bool, bias: bool) -> Layer {
+        Layer::with_rng(
+            in_dim,
+            out_dim,
+            Activation::from_nonlinear(nonlinear),
+            bias,
+            Init::Uniform,
+            &mut rand::thread_rng(),
+        )
+    }
+    /// Layer with weights initialized by `init` from `rng`.
+    fn with_rng(
+        in_dim: usize,
+        out_dim: usize,
+        activation: Activation,
+        bias: bool,
+        init: Init,
+        rng: &mut impl Rng,
+    ) -> Layer {
+        let bound = init.bound(in_dim, out_dim);
         Layer {
             neurons: (0..out_dim)
-                .map(|_| Neuron::new(in_dim, nonlinear))
+                .map(|_| Neuron::with_rng(in_dim, activation, bias, bound, rng))
                 .collect(),
+            init,
         }
     }
+    /// Redraws the weights per `init` from `rng`, in the same order as
+    /// `with_rng`, and zeroes the biases.
+    fn reset_parameters(&self, rng: &mut impl Rng) {
+        let in_dim = self.neurons.first().map_or(0, |neuron| neuron.in_dim);
+        let bound = self.init.bound(in_dim, self.neurons.len());
+        for neuron in self.neurons.iter() {
+            for wi in neuron.weights.iter() {
+                wi.set_data(uniform_sample(-bound..=bound, rng));
+            }
+            if let Some(bias) = &neuron.bias {
+                bias.set_data(0.0);
+            }
+        }
+    }
+    /// Builds a layer from known values, e.g. pretrained weights.
+    /// `weights[i]` is the weight vector of neuron `i` and `biases[i]` its bias.
+    pub fn from_weights(weights: Vec<Vec<f32>>, biases: Vec<f32>, nonlinear: bool) -> Layer {
+        assert!(!weights.is_empty(), "layer must have at least one neuron");
+        assert_eq!(weights.len(), biases.len(), "expected one bias per neuron");
+        let in_dim = weights[0].len();
+        assert!(
+            weights.iter().all(|w| w.len() == in_dim),
+            "all neurons must have the same number of weights"
+        );
+        Layer {
+            neurons: zip(weights, biases)
+                .map(|(w, b)| Neuron {
+                    weights: w.into_iter().map(Parameter::from_scalar).collect(),
+                    bias: Some(Parameter::from_scalar(b)),
+                    activation: Activation::from_nonlinear(nonlinear),
+                    in_dim,
+                })
+                .collect(),
+            init: Init::Uniform,
+        }
+    }
+    /// Parameters of each neuron in neuron order.
     fn parameters(&self) -> Vec<Parameter> {
         self.neurons
             .iter()
             .flat_map(|neuron| neuron.parameters())
             .collect()
     }
+    /// Every neuron gets its own clone of `x`. Cloning a Parameter only bumps
+    /// the `Arc` count of the shared node and never copies a `Value`: the
+    /// clones cost `in_dim * out_dim` increments, and each neuron's graph
+    /// holds every input twice (operand list and backward closure).
     pub fn forward(&self, x: Vec<Parameter>) -> Vec<Parameter> {
         self.neurons
-            .clone()
             .iter()
             .map(|neuron| neuron.forward(x.clone()))
             .collect()
     }
+    pub fn forward_eval(&self, x: &[f32]) -> Vec<f32> {
+        self.neurons
+            .iter()
+            .map(|neuron| neuron.forward_eval(x))
+            .collect()
+    }
+    /// Evaluates a flattened `(n, in_dim)` batch into a flattened
+    /// `(n, out_dim)` batch. Weights are read once per batch into a flat
+    /// `(out_dim, in_dim)` matrix so the inner loops work on plain slices.
+    fn forward_eval_flat(&self, xs: &[f32], n: usize) -> Vec<f32> {
+        let out_dim = self.neurons.len();
+        let in_dim = xs.len() / n;
+        let weights: Vec<f32> = self
+            .neurons
+            .iter()
+            .flat_map(|neuron| neuron.weights.iter().map(|wi| wi.data()))
+            .collect();
+        // a bias-only layer has no weights regardless of the input width
+        let w_dim = weights.len() / out_dim;
+        let biases: Vec<f32> = self.neurons.iter().map(|n| n.bias_data()).collect();
+
+        let mut out = vec![0.0; n * out_dim];
+        // rows by index rather than `chunks_exact`, which rejects empty rows
+        for (row, out_row) in out.chunks_exact_mut(out_dim).enumerate() {
+            let x = &xs[row * in_dim..(row + 1) * in_dim];
+            for j in 0..out_dim {
+                let w = &weights[j * w_dim..(j + 1) * w_dim];
+                let act = zip(w, x).fold(biases[j], |sum, (wi, xi)| sum + wi * xi);
+                out_row[j] = self.neurons[j].activate(act);
+            }
+        }
+        out
+    }
 }
 
 pub struct MLP {
+    /// Input width, kept separately so that models without layers have one.
+    in_dim: usize,
     layers: Vec<Layer>,
 }
 
+/// Declarative description of an MLP, see `MLP::from_config`.
+#[derive(Debug, Clone)]
+pub struct MlpConfig {
+    /// Input dimension followed by the output dimension of every layer.
+    pub dims: Vec<usize>,
+    pub hidden_activation: Activation,
+    pub output_activation: Activation,
+    pub init: Init,
+    /// Seed for the weight initialization.
+    pub seed: u64,
+}
+
+/// Step by step construction of an MLP with a different activation per
+/// layer, e.g. `MlpBuilder::new().input(2).layer(16, Activation::ReLU).build()`.
+#[derive(Debug, Clone)]
+pub struct MlpBuilder {
+    in_dim: Option<usize>,
+    layers: Vec<(usize, Activation)>,
+    init: Init,
+    seed: Option<u64>,
+}
+
+impl Default for MlpBuilder {
+    fn default() -> MlpBuilder {
+        MlpBuilder::new()
+    }
+}
+
+impl MlpBuilder {
+    /// Builder with uniform initialization and a random seed.
+    pub fn new() -> MlpBuilder {
+        MlpBuilder {
+            in_dim: None,
+            layers: vec![],
+            init: Init::Uniform,
+            seed: None,
+        }
+    }
+    /// Input dimension of the network.
+    pub fn input(mut self, dim: usize) -> MlpBuilder {
+        self.in_dim = Some(dim);
+        self
+    }
+    /// Appends a layer of `out_dim` neurons.
+    pub fn layer(mut self, out_dim: usize, activation: Activation) -> MlpBuilder {
+        self.layers.push((out_dim, activation));
+        self
+    }
+    pub fn init(mut self, init: Init) -> MlpBuilder {
+        self.init = init;
+        self
+    }
+    /// Seed for the weight initialization, making `build` reproducible.
+    pub fn seed(mut self, seed: u64) -> MlpBuilder {
+        self.seed = Some(seed);
+        self
+    }
+    pub fn build(&self) -> MLP {
+        let model_in_dim = self.in_dim.expect("input dimension not set");
+        let mut in_dim = model_in_dim;
+        assert!(!self.layers.is_empty(), "expected at least one layer");
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut layers = vec![];
+        for (out_dim, activation) in self.layers.iter() {
+            layers.push(Layer::with_rng(
+                in_dim,
+                *out_dim,
+                *activation,
+                true,
+                self.init,
+                &mut rng,
+            ));
+            in_dim = *out_dim;
+        }
+        MLP {
+            in_dim: model_in_dim,
+            layers,
+        }
+    }
+}
+
+/// Per-epoch metrics recorded by `MLP::fit`.
+#[derive(Debug, Default)]
+pub struct History {
+    pub losses: Vec<f32>,
+    pub accuracies: Vec<f32>,
+    pub epoch_times_ms: Vec<u128>,
+    /// Time spent in the forward pass (including the loss) per epoch.
+    pub forward_ms: Vec<f64>,
+    /// Time spent in `zero_grad` and the backward pass per epoch.
+    pub backward_ms: Vec<f64>,
+    /// Time spent in the parameter update per epoch.
+    pub step_ms: Vec<f64>,
+    /// L2 norm of the change in weights per epoch, see `snapshot_delta_norm`.
+    pub weight_deltas: Vec<f32>,
+}
+
+impl History {
+    /// Writes one `epoch,loss,accuracy,epoch_time_ms` row per epoch, after a
+    /// header row, to the file at `path`.
+    pub fn to_csv(&self, path: &str) -> io::Result<()> {
+        let mut csv = String::from("epoch,loss,accuracy,epoch_time_ms\n");
+        for (epoch, (loss, (acc, time))) in
+            zip(&self.losses, zip(&self.accuracies, &self.epoch_times_ms)).enumerate()
+        {
+            csv.push_str(&format!("{},{},{},{}\n", epoch, loss, acc, time));
+        }
+        fs::write(path, csv)
+    }
+}
+
+/// Micro-benchmark of training throughput. Runs a forward and a backward
+/// pass of `model` on each of `n_samples` random inputs and returns the mean
+/// forward and backward time per sample in milliseconds. Gradients are reset
+/// afterwards.
+pub fn bench_forward_backward(model: &MLP, n_samples: usize) -> (f64, f64) {
+    assert!(n_samples > 0, "need at least one sample");
+    let timings = bench_iterations(model, n_samples);
+    let n = timings.len() as f64;
+    let forward_ms = timings.iter().map(|(fwd, _)| fwd).sum::<f64>() / n;
+    let backward_ms = timings.iter().map(|(_, bwd)| bwd).sum::<f64>() / n;
+    (forward_ms, backward_ms)
+}
+
+/// Forward and backward time in milliseconds of every benchmark iteration.
+fn bench_iterations(model: &MLP, n_samples: usize) -> Vec<(f64, f64)> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let in_dim = model.dims()[0];
+    let ms = |from: Instant, to: Instant| (to - from).as_secs_f64() * 1000.0;
+    let timings = (0..n_samples)
+        .map(|_| {
+            let x: Vec<Parameter> = (0..in_dim)
+                .map(|_| Parameter::from_scalar(uniform_sample(-1.0..=1.0, &mut rng)))
+                .collect();
+            let start = Instant::now();
+            let out: Parameter = model.forward(x).into_iter().sum();
+            let forward_end = Instant::now();
+            out.backward();
+            let backward_end = Instant::now();
+            (ms(start, forward_end), ms(forward_end, backward_end))
+        })
+        .collect();
+    model.zero_grad();
+    timings
+}
+
+/// L2 norm of the difference of two snapshots, e.g. how far the weights
+/// moved during an epoch. Small values indicate convergence.
+pub fn snapshot_delta_norm(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len(), "snapshots must have the same length");
+    zip(a, b)
+        .map(|(ai, bi)| (ai - bi).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Concurrency settings for `MLP::forward_batch`.
+pub struct ThreadConfig {
+    /// Number of worker threads. `1` runs sequentially on the caller's thread.
+    pub workers: usize,
+}
+
+impl Default for ThreadConfig {
+    /// One worker per available CPU, or a single one without the `threads`
+    /// feature.
+    fn default() -> ThreadConfig {
+        #[cfg(feature = "threads")]
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        #[cfg(not(feature = "threads"))]
+        let workers = 1;
+        ThreadConfig { workers }
+    }
+}
+
 impl MLP {
     pub fn new(dims: Vec<usize>) -> MLP {
         let n_dims = dims.len() - 1;
         MLP {
+            in_dim: dims[0],
             layers: (0..n_dims)
-                .map(|i| Layer::new(dims[i], dims[i + 1], i != (n_dims - 1)))
+                .map(|i| Layer::new(dims[i], dims[i + 1], i != (n_dims - 1), true))
                 .collect(),
         }
     }
+    /// Builds an MLP from `cfg`. The same config always gives the same weights.
+    pub fn from_config(cfg: &MlpConfig) -> MLP {
+        assert!(
+            cfg.dims.len() >= 2,
+            "expected an input and an output dimension"
+        );
+        let n_layers = cfg.dims.len() - 1;
+        let mut builder = MlpBuilder::new()
+            .input(cfg.dims[0])
+            .init(cfg.init)
+            .seed(cfg.seed);
+        for (i, out_dim) in cfg.dims.iter().skip(1).enumerate() {
+            let activation = if i == n_layers - 1 {
+                cfg.output_activation
+            } else {
+                cfg.hidden_activation
+            };
+            builder = builder.layer(*out_dim, activation);
+        }
+        builder.build()
+    }
+    /// Reinitializes all weights in place per each layer's init scheme and
+    /// zeroes the biases. The Parameter handles stay the same, so optimizers
+    /// holding them keep working. With the same seed and init this gives the
+    /// weights of `MlpBuilder::seed(seed).build()`.
+    pub fn reset_parameters(&self, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        for layer in self.layers.iter() {
+            layer.reset_parameters(&mut rng);
+        }
+    }
+    /// Input dimension followed by the output dimension of every layer.
+    pub fn dims(&self) -> Vec<usize> {
+        let mut dims = vec![self.in_dim];
+        dims.extend(self.layers.iter().map(|layer| layer.neurons.len()));
+        dims
+    }
+    /// Output width after each layer for an input of `input_dim` features,
+    /// without running any data through the model. Errors naming the first
+    /// layer whose neurons don't take the width produced before it.
+    pub fn shape_trace(&self, input_dim: usize) -> Result<Vec<usize>, String> {
+        let mut width = input_dim;
+        let mut shapes = vec![];
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(neuron) = layer.neurons.iter().find(|n| n.in_dim != width) {
+                return Err(format!(
+                    "layer {} expects {} inputs but gets {}",
+                    i, neuron.in_dim, width
+                ));
+            }
+            width = layer.neurons.len();
+            shapes.push(width);
+        }
+        Ok(shapes)
+    }
+    /// Activation of every layer.
+    pub fn activations(&self) -> Vec<Activation> {
+        self.layers
+            .iter()
+            .map(|layer| layer.neurons[0].activation)
+            .collect()
+    }
     /// Forward pass for MLP.
     /// `x` is an n-dimensional datapoint.
     pub fn forward(&self, mut x: Vec<Parameter>) -> Vec<Parameter> {
@@ -104,17 +534,315 @@ impl MLP {
         }
         x
     }
+    /// Forward pass through layers `0..=layer_idx` only, returning the
+    /// activations of layer `layer_idx`.
+    pub fn forward_until(&self, mut x: Vec<Parameter>, layer_idx: usize) -> Vec<Parameter> {
+        assert!(layer_idx < self.layers.len(), "layer index out of range");
+        for layer in self.layers.iter().take(layer_idx + 1) {
+            x = layer.forward(x);
+        }
+        x
+    }
+    /// Forward pass for a batch of datapoints, split across at most
+    /// `config.workers` threads. Outputs are in the same order as `xs`.
+    /// Without the `threads` feature the batch always runs sequentially.
+    pub fn forward_batch(&self, xs: &[Vec<f32>], config: &ThreadConfig) -> Vec<Vec<Parameter>> {
+        assert!(config.workers > 0, "need at least one worker");
+        let forward_one =
+            |x: &Vec<f32>| self.forward(x.iter().map(|xi| Parameter::from_scalar(*xi)).collect());
+        #[cfg(feature = "threads")]
+        if config.workers > 1 && xs.len() > 1 {
+            let chunk_size = xs.len().div_ceil(config.workers);
+            return thread::scope(|s| {
+                let handles: Vec<_> = xs
+                    .chunks(chunk_size)
+                    .map(|chunk| s.spawn(move || chunk.iter().map(forward_one).collect::<Vec<_>>()))
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+        }
+        xs.iter().map(forward_one).collect()
+    }
+    /// Forward pass on raw values without building a graph, for inference.
+    pub fn forward_eval(&self, x: &[f32]) -> Vec<f32> {
+        let mut x = x.to_vec();
+        for layer in self.layers.iter() {
+            x = layer.forward_eval(&x);
+        }
+        x
+    }
+    /// Evaluates the first output of the model on a `steps x steps` grid
+    /// spanning `x_range` and `y_range`. `grid[i][j]` is the output at the
+    /// `j`th x value and the `i`th y value.
+    pub fn grid_eval(
+        &self,
+        x_range: (f32, f32),
+        y_range: (f32, f32),
+        steps: usize,
+    ) -> Vec<Vec<f32>> {
+        assert!(steps > 1, "grid needs at least two steps per axis");
+        let xs = linspace(x_range.0, x_range.1, steps);
+        linspace(y_range.0, y_range.1, steps)
+            .into_iter()
+            .map(|y| xs.iter().map(|x| self.forward_eval(&[*x, y])[0]).collect())
+            .collect()
+    }
+    /// Approximate decision boundary on a `steps x steps` grid (see
+    /// `grid_eval`). Returns the midpoints between horizontally or vertically
+    /// adjacent grid points where the sign of the output changes.
+    pub fn decision_contour(
+        &self,
+        x_range: (f32, f32),
+        y_range: (f32, f32),
+        steps: usize,
+    ) -> Vec<(f32, f32)> {
+        let grid = self.grid_eval(x_range, y_range, steps);
+        let xs = linspace(x_range.0, x_range.1, steps);
+        let ys = linspace(y_range.0, y_range.1, steps);
+        let crosses = |a: f32, b: f32| (a > 0.0) != (b > 0.0);
+        let mut contour = vec![];
+        for i in 0..steps {
+            for j in 0..steps {
+                if j + 1 < steps && crosses(grid[i][j], grid[i][j + 1]) {
+                    contour.push(((xs[j] + xs[j + 1]) / 2.0, ys[i]));
+                }
+                if i + 1 < steps && crosses(grid[i][j], grid[i + 1][j]) {
+                    contour.push((xs[j], (ys[i] + ys[i + 1]) / 2.0));
+                }
+            }
+        }
+        contour
+    }
+    /// Batched `forward_eval`. The batch is kept in flat row-major buffers
+    /// which the compiler can autovectorize. Panics if a row doesn't have
+    /// the model's input width, or for models that ignore their input, if
+    /// the rows differ in width.
+    pub fn forward_eval_batch(&self, xs: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let n = xs.len();
+        if n == 0 {
+            return vec![];
+        }
+        // a bias-only first layer takes inputs of any width
+        let in_dim = if self.in_dim > 0 {
+            self.in_dim
+        } else {
+            xs[0].len()
+        };
+        assert!(
+            xs.iter().all(|x| x.len() == in_dim),
+            "every row must have {} features",
+            in_dim
+        );
+        let mut flat: Vec<f32> = xs.concat();
+        for layer in self.layers.iter() {
+            flat = layer.forward_eval_flat(&flat, n);
+        }
+        let out_dim = flat.len() / n;
+        flat.chunks_exact(out_dim).map(|row| row.to_vec()).collect()
+    }
     /// Zero gradients for all neuron parameters.
-    pub fn zero_grad(&self) -> () {
+    pub fn zero_grad(&self) {
         for mut param in self.parameters() {
             param.zero_grad();
         }
     }
-    pub fn lr_step(&self, new_lr: f32) -> () {
+    pub fn lr_step(&self, new_lr: f32) {
         for mut param in self.parameters() {
             param.lr_step(new_lr);
         }
     }
+    /// Gradient centralization: subtracts from every trainable weight
+    /// gradient the mean over the trainable weights of its neuron, so those
+    /// sum to zero. Frozen (e.g. pruned) weights and biases are left alone.
+    /// Call between `backward` and the optimizer step.
+    pub fn centralize_gradients(&self) {
+        for neuron in self.layers.iter().flat_map(|layer| layer.neurons.iter()) {
+            let trainable: Vec<&Parameter> = neuron
+                .weights
+                .iter()
+                .filter(|wi| wi.requires_grad())
+                .collect();
+            if trainable.is_empty() {
+                continue;
+            }
+            let mean = trainable.iter().map(|wi| wi.grad()).sum::<f32>() / trainable.len() as f32;
+            for wi in trainable {
+                wi.map_grad(|grad| grad - mean);
+            }
+        }
+    }
+    /// Baseline model with a single bias-only layer: it ignores its input and
+    /// outputs `out_dim` learnable constants.
+    pub fn constant_predictor(out_dim: usize) -> MLP {
+        MLP {
+            in_dim: 0,
+            layers: vec![Layer::new(0, out_dim, false, true)],
+        }
+    }
+    /// Full-batch training with `loss` for `epochs` epochs at learning rate
+    /// `lr`. Returns the loss, accuracy and duration of every epoch.
+    pub fn fit(&self, x: &[Vec<f32>], y: &[f32], epochs: usize, lr: f32) -> History {
+        let y = y.to_vec();
+        let mut history = History::default();
+        for _ in 0..epochs {
+            let before = self.snapshot();
+            let start = Instant::now();
+            let preds: Vec<Parameter> = x
+                .iter()
+                .flat_map(|xi| {
+                    self.forward(xi.iter().map(|v| Parameter::from_scalar(*v)).collect())
+                })
+                .collect();
+            let (total_loss, acc) = loss(self, preds, &y);
+            let forward_end = Instant::now();
+            self.zero_grad();
+            total_loss.backward();
+            let backward_end = Instant::now();
+            self.lr_step(lr);
+            let step_end = Instant::now();
+
+            let ms = |from: Instant, to: Instant| (to - from).as_secs_f64() * 1000.0;
+            history.losses.push(total_loss.data());
+            history.accuracies.push(acc);
+            history.epoch_times_ms.push(start.elapsed().as_millis());
+            history.forward_ms.push(ms(start, forward_end));
+            history.backward_ms.push(ms(forward_end, backward_end));
+            history.step_ms.push(ms(backward_end, step_end));
+            history
+                .weight_deltas
+                .push(snapshot_delta_norm(&before, &self.snapshot()));
+        }
+        history
+    }
+    /// `fit` on all samples of `data`.
+    pub fn fit_dataset(&self, data: &dyn Dataset, epochs: usize, lr: f32) -> History {
+        let (x, y): (Vec<Vec<f32>>, Vec<f32>) = (0..data.len()).map(|i| data.get(i)).unzip();
+        self.fit(&x, &y, epochs, lr)
+    }
+    /// Predicted class labels, 1.0 or -1.0 by the sign of the first output.
+    pub fn predict(&self, x: &[Vec<f32>]) -> Vec<f32> {
+        self.forward_eval_batch(x)
+            .iter()
+            .map(|out| {
+                if predicts_positive(out[0], 0.0) {
+                    1.0
+                } else {
+                    -1.0
+                }
+            })
+            .collect()
+    }
+    /// Class probabilities for `x` with the logits divided by `temperature`
+    /// first. A single output gives `[sigmoid]`, the probability of the
+    /// positive class; several outputs give their softmax. Temperatures above
+    /// 1 soften the probabilities, below 1 sharpen them.
+    pub fn predict_proba(&self, x: &[f32], temperature: f32) -> Vec<f32> {
+        assert!(temperature > 0.0, "temperature must be positive");
+        let logits: Vec<f32> = self
+            .forward_eval(x)
+            .iter()
+            .map(|logit| logit / temperature)
+            .collect();
+        if logits.len() == 1 {
+            return vec![Activation::Sigmoid.apply_eval(logits[0])];
+        }
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exps: Vec<f32> = logits.iter().map(|logit| (logit - max).exp()).collect();
+        let total: f32 = exps.iter().sum();
+        exps.iter().map(|e| e / total).collect()
+    }
+    /// Trains with `fit` and returns `predict` on the training inputs.
+    pub fn fit_predict(&self, x: &[Vec<f32>], y: &[f32], epochs: usize, lr: f32) -> Vec<f32> {
+        self.fit(x, y, epochs, lr);
+        self.predict(x)
+    }
+    /// Online training on a single sample: forward pass, `loss`, backward pass
+    /// and a gradient step with learning rate `lr`. Returns the sample loss.
+    pub fn partial_fit(&self, x: &[f32], y: f32, lr: f32) -> f32 {
+        let preds = self.forward(x.iter().map(|xi| Parameter::from_scalar(*xi)).collect());
+        let (total_loss, _) = loss(self, preds, &vec![y]);
+        self.zero_grad();
+        total_loss.backward();
+        self.lr_step(lr);
+        total_loss.data()
+    }
+    /// Clamps every parameter into `[lo, hi]`, e.g. after `lr_step`.
+    pub fn clamp_weights(&self, lo: f32, hi: f32) {
+        assert!(lo <= hi, "lo must not exceed hi");
+        for param in self.parameters() {
+            param.map_data(|data| data.clamp(lo, hi));
+        }
+    }
+    /// Magnitude pruning. Zeroes the `fraction` of parameters with the smallest
+    /// absolute value and freezes them so they stay zero during training.
+    pub fn prune(&self, fraction: f32) {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "fraction must be in [0, 1]"
+        );
+        let mut params = self.parameters();
+        params.sort_by(|a, b| a.data().abs().total_cmp(&b.data().abs()));
+        let n_pruned = (fraction * params.len() as f32) as usize;
+        for param in params.iter().take(n_pruned) {
+            param.set_data(0.0);
+            param.set_requires_grad(false);
+        }
+    }
+    /// Fraction of parameters with `|data| < 1e-8`, e.g. after `prune`.
+    /// Biases count too and start at zero.
+    pub fn sparsity(&self) -> f32 {
+        let params = self.parameters();
+        if params.is_empty() {
+            return 0.0;
+        }
+        let n_zero = params.iter().filter(|p| p.data().abs() < 1e-8).count();
+        n_zero as f32 / params.len() as f32
+    }
+    /// Panics if any parameter has a nonzero gradient, e.g. to check that
+    /// no stale gradients are left over before a backward pass.
+    pub fn assert_grads_zero(&self) {
+        for (i, param) in self.parameters().iter().enumerate() {
+            assert_eq!(param.grad(), 0.0, "parameter {} has a nonzero gradient", i);
+        }
+    }
+    /// Parameters split into named groups, `"weights"` and `"biases"`, so
+    /// optimizers can treat them differently (e.g. no weight decay on biases).
+    pub fn parameter_groups(&self) -> Vec<(String, Vec<Parameter>)> {
+        let neurons = || self.layers.iter().flat_map(|layer| layer.neurons.iter());
+        let weights = neurons()
+            .flat_map(|neuron| neuron.weights.iter().cloned())
+            .collect();
+        let biases = neurons()
+            .flat_map(|neuron| neuron.bias.iter().cloned())
+            .collect();
+        vec![
+            (String::from("weights"), weights),
+            (String::from("biases"), biases),
+        ]
+    }
+    /// Data of all parameters, in `parameters` order.
+    pub fn snapshot(&self) -> Vec<f32> {
+        self.parameters().iter().map(|param| param.data()).collect()
+    }
+    /// Gradients of all parameters, in `parameters` order.
+    pub fn gradients(&self) -> Vec<f32> {
+        self.parameters().iter().map(|param| param.grad()).collect()
+    }
+    /// Overwrites all parameters from a flat vector in `parameters` order,
+    /// e.g. one produced by `snapshot` or an external optimizer.
+    pub fn set_from_flat(&self, params: &[f32]) {
+        let own = self.parameters();
+        assert_eq!(own.len(), params.len(), "expected one value per parameter");
+        for (param, data) in zip(own, params) {
+            param.set_data(*data);
+        }
+    }
+    /// All parameters of the model in a stable order: for each layer, for each
+    /// neuron, its weights followed by its bias (if it has one). Anything that flattens the
+    /// model (serialization, optimizer state) relies on this order.
     pub fn parameters(&self) -> Vec<Parameter> {
         self.layers
             .iter()
@@ -130,12 +858,7 @@ impl fmt::Display for MLP {
             msg.push_str("\nLayer:");
             for neuron in layer.neurons.iter() {
                 msg.push_str(
-                    format!(
-                        "\nNeuron: ({}, {})",
-                        neuron.in_dim,
-                        if neuron.nonlinear { "ReLU" } else { "Linear" },
-                    )
-                    .as_str(),
+                    format!("\nNeuron: ({}, {:?})", neuron.in_dim, neuron.activation,).as_str(),
                 );
             }
         }
@@ -143,34 +866,1478 @@ impl fmt::Display for MLP {
     }
 }
 
+/// A building block of a network mapping one datapoint to another.
+pub trait Module {
+    fn forward(&self, x: Vec<Parameter>) -> Vec<Parameter>;
+    fn parameters(&self) -> Vec<Parameter>;
+}
+
+impl Module for Layer {
+    fn forward(&self, x: Vec<Parameter>) -> Vec<Parameter> {
+        Layer::forward(self, x)
+    }
+    fn parameters(&self) -> Vec<Parameter> {
+        Layer::parameters(self)
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, x: Vec<Parameter>) -> Vec<Parameter> {
+        MLP::forward(self, x)
+    }
+    fn parameters(&self) -> Vec<Parameter> {
+        MLP::parameters(self)
+    }
+}
+
+/// Inference-only view of a model: raw outputs for a single datapoint
+/// without building a graph, e.g. for serving a `Box<dyn Predictor>`.
+/// Unlike `MLP::predict` the outputs are not turned into labels.
+pub trait Predictor {
+    fn predict_raw(&self, x: &[f32]) -> Vec<f32>;
+}
+
+impl Predictor for MLP {
+    fn predict_raw(&self, x: &[f32]) -> Vec<f32> {
+        self.forward_eval(x)
+    }
+}
+
+/// Averages the outputs of several models with the same output dimension.
+pub struct Ensemble {
+    models: Vec<MLP>,
+}
+
+impl Ensemble {
+    pub fn new(models: Vec<MLP>) -> Ensemble {
+        assert!(!models.is_empty(), "ensemble needs at least one model");
+        let out_dim = |model: &MLP| *model.dims().last().unwrap();
+        assert!(
+            models
+                .iter()
+                .all(|model| out_dim(model) == out_dim(&models[0])),
+            "all models must have the same output dimension"
+        );
+        Ensemble { models }
+    }
+    /// Mean of the model outputs per output, built in the graph so the
+    /// models can be fine-tuned jointly.
+    pub fn forward(&self, x: Vec<Parameter>) -> Vec<Parameter> {
+        let scale = Parameter::constant(1.0 / self.models.len() as f32);
+        let outputs: Vec<Vec<Parameter>> = self
+            .models
+            .iter()
+            .map(|model| model.forward(x.clone()))
+            .collect();
+        (0..outputs[0].len())
+            .map(|i| outputs.iter().map(|out| out[i].clone()).sum::<Parameter>() * scale.clone())
+            .collect()
+    }
+    /// Mean of the models' `forward_eval` outputs.
+    pub fn predict_raw(&self, x: &[f32]) -> Vec<f32> {
+        let mut mean = self.models[0].forward_eval(x);
+        for model in self.models.iter().skip(1) {
+            for (m, out) in zip(mean.iter_mut(), model.forward_eval(x)) {
+                *m += out;
+            }
+        }
+        let n = self.models.len() as f32;
+        mean.iter().map(|m| m / n).collect()
+    }
+}
+
+impl Module for Ensemble {
+    fn forward(&self, x: Vec<Parameter>) -> Vec<Parameter> {
+        Ensemble::forward(self, x)
+    }
+    fn parameters(&self) -> Vec<Parameter> {
+        self.models
+            .iter()
+            .flat_map(|model| model.parameters())
+            .collect()
+    }
+}
+
+impl Predictor for Ensemble {
+    fn predict_raw(&self, x: &[f32]) -> Vec<f32> {
+        Ensemble::predict_raw(self, x)
+    }
+}
+
+/// Inverted dropout. While training, zeroes each input with probability `p`
+/// and scales the kept ones by `1 / (1 - p)`. Identity when not training.
+pub struct Dropout {
+    p: f32,
+    training: bool,
+    /// Draws the masks; behind a mutex since `forward` takes `&self`.
+    rng: Mutex<StdRng>,
+}
+
+impl Dropout {
+    pub fn new(p: f32) -> Dropout {
+        Dropout::with_rng(p, StdRng::from_entropy())
+    }
+    /// Dropout whose masks are reproducible from `seed`.
+    pub fn with_seed(p: f32, seed: u64) -> Dropout {
+        Dropout::with_rng(p, StdRng::seed_from_u64(seed))
+    }
+    fn with_rng(p: f32, rng: StdRng) -> Dropout {
+        assert!((0.0..1.0).contains(&p), "p must be in [0, 1)");
+        Dropout {
+            p,
+            training: true,
+            rng: Mutex::new(rng),
+        }
+    }
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+impl Module for Dropout {
+    fn forward(&self, x: Vec<Parameter>) -> Vec<Parameter> {
+        if !self.training || self.p == 0.0 {
+            return x;
+        }
+        let scale = 1.0 / (1.0 - self.p);
+        let mut rng = self
+            .rng
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        x.into_iter()
+            .map(|xi| {
+                let keep = rng.gen::<f32>() >= self.p;
+                xi * Parameter::constant(if keep { scale } else { 0.0 })
+            })
+            .collect()
+    }
+    fn parameters(&self) -> Vec<Parameter> {
+        vec![]
+    }
+}
+
+/// Fixed input normalization `(x - mean) / std`, e.g. with the statistics
+/// returned by `standardize`. Has no trainable parameters, so a model
+/// starting with it carries its own preprocessing.
+pub struct Normalize {
+    mean: Vec<f32>,
+    std: Vec<f32>,
+}
+
+impl Normalize {
+    /// A zero `std`, i.e. a constant feature, is replaced by 1 as in
+    /// `standardize`.
+    pub fn new(mean: Vec<f32>, std: Vec<f32>) -> Normalize {
+        assert_eq!(mean.len(), std.len(), "expected one std per mean");
+        let std = std
+            .into_iter()
+            .map(|s| if s == 0.0 { 1.0 } else { s })
+            .collect();
+        Normalize { mean, std }
+    }
+}
+
+impl Module for Normalize {
+    fn forward(&self, x: Vec<Parameter>) -> Vec<Parameter> {
+        assert_eq!(x.len(), self.mean.len(), "input has the wrong dimension");
+        zip(x, zip(&self.mean, &self.std))
+            .map(|(xi, (mean, std))| (xi - Parameter::constant(*mean)) / Parameter::constant(*std))
+            .collect()
+    }
+    fn parameters(&self) -> Vec<Parameter> {
+        vec![]
+    }
+}
+
+/// Chains modules, feeding the output of each into the next.
+pub struct Sequential {
+    modules: Vec<Box<dyn Module>>,
+}
+
+impl Sequential {
+    pub fn new(modules: Vec<Box<dyn Module>>) -> Sequential {
+        Sequential { modules }
+    }
+}
+
+impl Module for Sequential {
+    fn forward(&self, mut x: Vec<Parameter>) -> Vec<Parameter> {
+        for module in self.modules.iter() {
+            x = module.forward(x);
+        }
+        x
+    }
+    fn parameters(&self) -> Vec<Parameter> {
+        self.modules
+            .iter()
+            .flat_map(|module| module.parameters())
+            .collect()
+    }
+}
+
+/// Default strength of the l2 regularization in `loss`.
+const DEFAULT_ALPHA: f32 = 1e-4;
+
+/// Mean svm max margin loss plus l2 regularization, and the accuracy of
+/// `preds`. Empty `preds` give a zero data loss and zero accuracy.
 pub fn loss(model: &MLP, preds: Vec<Parameter>, y: &Vec<f32>) -> (Parameter, f32) {
+    margin_loss(model, preds, y, 1.0, 0.0, DEFAULT_ALPHA, 1, (1.0, 1.0))
+}
+
+/// `loss` with label smoothing. Hard targets `y` in {-1, 1} are shrunk toward
+/// zero as `y * (1 - label_smoothing)`, so even a prediction equal to its hard
+/// target keeps a small positive margin loss.
+pub fn loss_smoothed(
+    model: &MLP,
+    preds: Vec<Parameter>,
+    y: &Vec<f32>,
+    label_smoothing: f32,
+) -> (Parameter, f32) {
+    margin_loss(
+        model,
+        preds,
+        y,
+        1.0,
+        label_smoothing,
+        DEFAULT_ALPHA,
+        1,
+        (1.0, 1.0),
+    )
+}
+
+/// `loss` with a configurable l2 regularization strength `alpha`.
+/// With `alpha == 0.0` the regularization term is left out of the graph.
+pub fn loss_regularized(
+    model: &MLP,
+    preds: Vec<Parameter>,
+    y: &Vec<f32>,
+    alpha: f32,
+) -> (Parameter, f32) {
+    margin_loss(model, preds, y, 1.0, 0.0, alpha, 1, (1.0, 1.0))
+}
+
+/// `loss` with a configurable hinge `margin` (1.0 in `loss`) and l2
+/// regularization strength `alpha`.
+pub fn loss_margin(
+    model: &MLP,
+    preds: Vec<Parameter>,
+    y: &Vec<f32>,
+    margin: f32,
+    alpha: f32,
+) -> (Parameter, f32) {
+    margin_loss(model, preds, y, margin, 0.0, alpha, 1, (1.0, 1.0))
+}
+
+/// Squared hinge (L2-SVM) loss, `mean(relu(1 - y * pred)^2)` plus l2
+/// regularization with strength `alpha`. Unlike `loss` its gradient is
+/// continuous at the margin.
+pub fn squared_hinge_loss(
+    model: &MLP,
+    preds: Vec<Parameter>,
+    y: &Vec<f32>,
+    alpha: f32,
+) -> (Parameter, f32) {
+    margin_loss(model, preds, y, 1.0, 0.0, alpha, 2, (1.0, 1.0))
+}
+
+/// Mean svm max margin loss with every sample's hinge term scaled by the
+/// weight of its class, `class_weights.0` for negative and `class_weights.1`
+/// for positive labels, plus l2 regularization with strength `alpha`.
+/// Upweighting the minority class counters class imbalance.
+pub fn class_weighted_loss(
+    model: &MLP,
+    preds: Vec<Parameter>,
+    y: &Vec<f32>,
+    class_weights: (f32, f32),
+    alpha: f32,
+) -> (Parameter, f32) {
+    margin_loss(model, preds, y, 1.0, 0.0, alpha, 1, class_weights)
+}
+
+/// Hinge loss raised to `hinge_power` per sample, scaled by the weight of
+/// the sample's class (negative, positive), averaged, plus l2 regularization.
+#[allow(clippy::too_many_arguments)]
+fn margin_loss(
+    model: &MLP,
+    preds: Vec<Parameter>,
+    y: &Vec<f32>,
+    margin: f32,
+    label_smoothing: f32,
+    alpha: f32,
+    hinge_power: i32,
+    class_weights: (f32, f32),
+) -> (Parameter, f32) {
+    assert!(
+        (0.0..=1.0).contains(&label_smoothing),
+        "label_smoothing must be in [0, 1]"
+    );
     // svm max margin loss
     let losses: Vec<Parameter> = zip(y, preds.clone())
-        .map(|(yi, pi)| (Parameter::from_scalar(1.0) + (-Parameter::from_scalar(*yi)) * pi).relu())
+        .map(|(yi, pi)| {
+            let target = *yi * (1.0 - label_smoothing);
+            let hinge = (Parameter::constant(margin) + Parameter::constant(-target) * pi).relu();
+            let hinge = if hinge_power == 1 {
+                hinge
+            } else {
+                hinge.powi(hinge_power)
+            };
+            let weight = if *yi > 0.0 {
+                class_weights.1
+            } else {
+                class_weights.0
+            };
+            if weight == 1.0 {
+                hinge
+            } else {
+                hinge * Parameter::constant(weight)
+            }
+        })
         .collect();
     let n = losses.len();
-    let data_loss = losses
+    let data_loss = match losses.into_iter().reduce(|acc, param| acc + param.clone()) {
+        Some(data_loss) => data_loss * (Parameter::constant(1.0) / Parameter::constant(n as f32)),
+        None => Parameter::from_scalar(0.0),
+    };
+
+    let total_loss = match l2_reg(model, alpha) {
+        Some(reg_loss) => data_loss + reg_loss,
+        None => data_loss,
+    };
+    let acc = accuracy(&preds, y, 0.0);
+
+    (total_loss, acc)
+}
+
+/// Pairwise margin loss averaged over all pairs of predictions.
+/// Same-class pairs are penalized by their squared difference, pushing them
+/// together. Different-class pairs are penalized by `relu(margin - d)` where
+/// `d` is the positive-class prediction minus the negative-class one, pushing
+/// them at least `margin` apart.
+pub fn pairwise_margin_loss(preds: &[Parameter], y: &[f32], margin: f32) -> Parameter {
+    assert_eq!(preds.len(), y.len(), "expected one label per prediction");
+    assert!(
+        preds.len() > 1,
+        "need at least two predictions to form a pair"
+    );
+    let mut pair_losses = vec![];
+    for i in 0..preds.len() {
+        for j in (i + 1)..preds.len() {
+            let diff = preds[i].clone() - preds[j].clone();
+            let pair_loss = if (y[i] > 0.0) == (y[j] > 0.0) {
+                diff.pow(2.0)
+            } else {
+                let d = if y[i] > 0.0 { diff } else { -diff };
+                (Parameter::from_scalar(margin) - d).relu()
+            };
+            pair_losses.push(pair_loss);
+        }
+    }
+    let n = pair_losses.len();
+    pair_losses.into_iter().reduce(|acc, l| acc + l).unwrap() / Parameter::from_scalar(n as f32)
+}
+
+/// The decision rule shared by `predict`, `accuracy` and `loss`: a
+/// prediction is positive if it is above `threshold`.
+fn predicts_positive(pred: f32, threshold: f32) -> bool {
+    pred > threshold
+}
+
+/// Whether `pred` is on the same side of `threshold` as `label` is of zero.
+fn sign_match(pred: f32, label: f32, threshold: f32) -> bool {
+    (label > 0.0) == predicts_positive(pred, threshold)
+}
+
+/// Fraction of predictions on the same side of `threshold` as their label.
+/// A prediction is positive if it is above `threshold`, a label if it is
+/// above zero, so both {-1, 1} and {0, 1} labels work. Use `0.0` for raw
+/// margins and `0.5` for sigmoid probabilities.
+pub fn accuracy(preds: &[Parameter], y: &[f32], threshold: f32) -> f32 {
+    assert_eq!(preds.len(), y.len(), "expected one label per prediction");
+    if preds.is_empty() {
+        return 0.0;
+    }
+    let n_true = zip(y, preds)
+        .filter(|(yi, pi)| sign_match(pi.data(), **yi, threshold))
+        .count();
+    (n_true as f32) / (preds.len() as f32)
+}
+
+/// Mean absolute error between predictions and regression targets.
+pub fn mae(preds: &[Parameter], targets: &[f32]) -> f32 {
+    assert_eq!(
+        preds.len(),
+        targets.len(),
+        "expected one target per prediction"
+    );
+    if preds.is_empty() {
+        return 0.0;
+    }
+    let total: f32 = zip(preds, targets)
+        .map(|(pi, ti)| (pi.data() - ti).abs())
+        .sum();
+    total / preds.len() as f32
+}
+
+/// Coefficient of determination, `1 - SS_res / SS_tot`. Returns 0.0 when
+/// the targets have zero variance (including no targets at all).
+pub fn r2_score(preds: &[Parameter], targets: &[f32]) -> f32 {
+    assert_eq!(
+        preds.len(),
+        targets.len(),
+        "expected one target per prediction"
+    );
+    if targets.is_empty() {
+        return 0.0;
+    }
+    let mean = targets.iter().sum::<f32>() / targets.len() as f32;
+    let ss_tot: f32 = targets.iter().map(|ti| (ti - mean).powi(2)).sum();
+    if ss_tot == 0.0 {
+        return 0.0;
+    }
+    let ss_res: f32 = zip(preds, targets)
+        .map(|(pi, ti)| (ti - pi.data()).powi(2))
+        .sum();
+    1.0 - ss_res / ss_tot
+}
+
+/// Fraction of samples whose true label is among the `k` highest logits.
+pub fn top_k_accuracy(logits_per_sample: &[Vec<Parameter>], labels: &[usize], k: usize) -> f32 {
+    assert_eq!(
+        logits_per_sample.len(),
+        labels.len(),
+        "expected one label per sample"
+    );
+    let n_correct = zip(logits_per_sample, labels)
+        .filter(|(logits, label)| {
+            let label_logit = logits[**label].data();
+            // rank of the true label, ties resolved in its favor
+            let rank = logits.iter().filter(|l| l.data() > label_logit).count();
+            rank < k
+        })
+        .count();
+    n_correct as f32 / labels.len() as f32
+}
+
+/// L2 regularization term `alpha * sum(p^2)` over the model parameters.
+/// Returns `None` for `alpha == 0.0` or a model without parameters so no
+/// graph is built for it.
+fn l2_reg(model: &MLP, alpha: f32) -> Option<Parameter> {
+    if alpha == 0.0 {
+        return None;
+    }
+    let sum_sq = model
+        .parameters()
         .into_iter()
-        .reduce(|acc, param| acc + param.clone())
-        .unwrap();
-    let data_loss = data_loss * (Parameter::from_scalar(1.0) / Parameter::from_scalar(n as f32));
-
-    // l2 regularization
-    let alpha = Parameter::from_scalar(1e-4);
-    let reg_loss = alpha
-        * model
+        .map(|param| param.clone() * param)
+        .reduce(|acc, param| acc + param)?;
+    Some(Parameter::constant(alpha) * sum_sq)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use assert_approx_eq::assert_approx_eq;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        autograd::{fold_constants, serialize_graph, Operation},
+        math::{make_moons_seeded, standardize},
+    };
+
+    fn train_step(model: &MLP, x: &[(f32, f32)], y: &Vec<f32>) {
+        let preds: Vec<Parameter> = x
+            .iter()
+            .flat_map(|(x1, x2)| {
+                model.forward(vec![
+                    Parameter::from_scalar(*x1),
+                    Parameter::from_scalar(*x2),
+                ])
+            })
+            .collect();
+        let (total_loss, _) = loss(model, preds, y);
+        model.zero_grad();
+        total_loss.backward();
+        model.lr_step(0.5);
+    }
+
+    #[test]
+    fn test_prune() {
+        let model = MLP::new(vec![2, 4, 1]);
+        // 17 parameters with fixed values, the 8 smallest in magnitude are
+        // the ones at indices 2, 3, 5, 7, 8, 10, 11 and 14
+        model.set_from_flat(&[
+            0.9, -0.8, 0.05, -0.1, 0.7, 0.15, -0.6, 0.2, -0.25, 0.5, 0.3, -0.35, 0.45, -0.55, 0.4,
+            0.65, -0.75,
+        ]);
+        let params = model.parameters();
+        let pruned: Vec<Parameter> = [2, 3, 5, 7, 8, 10, 11, 14]
+            .iter()
+            .map(|i| params[*i].clone())
+            .collect();
+
+        model.prune(0.5);
+        let n_zero = |model: &MLP| {
+            model
+                .parameters()
+                .iter()
+                .filter(|param| param.data() == 0.0)
+                .count()
+        };
+        assert_eq!(n_zero(&model), 8);
+        assert!(pruned.iter().all(|param| !param.requires_grad()));
+
+        let x = vec![(1.0, 0.5), (-1.0, -0.5), (0.5, -1.0), (-0.5, 1.0)];
+        let y = vec![1.0, -1.0, 1.0, -1.0];
+        train_step(&model, &x, &y);
+        assert!(pruned.iter().all(|param| param.data() == 0.0));
+        assert!(n_zero(&model) >= 8);
+    }
+
+    #[test]
+    fn test_parameters_order() {
+        let model = MLP::new(vec![2, 3, 1]);
+        let mut expected = vec![];
+        for layer in model.layers.iter() {
+            for neuron in layer.neurons.iter() {
+                expected.extend(neuron.weights.iter().cloned());
+                expected.extend(neuron.bias.clone());
+            }
+        }
+        let params = model.parameters();
+        assert_eq!(params.len(), expected.len());
+        assert!(zip(params, expected).all(|(p, e)| p == e));
+        // repeated calls agree
+        assert!(zip(model.parameters(), model.parameters()).all(|(a, b)| a == b));
+    }
+
+    #[test]
+    fn test_forward_eval_batch() {
+        let model = MLP::new(vec![2, 8, 8, 1]);
+        let xs: Vec<Vec<f32>> = (0..50)
+            .map(|i| vec![(i as f32) / 25.0 - 1.0, 1.0 - (i as f32) / 50.0])
+            .collect();
+        let batch = model.forward_eval_batch(&xs);
+        assert_eq!(batch.len(), xs.len());
+        for (x, out) in zip(xs.iter(), batch) {
+            let single = model.forward_eval(x);
+            let graph = model.forward(x.iter().map(|xi| Parameter::from_scalar(*xi)).collect());
+            assert_eq!(out.len(), 1);
+            assert_approx_eq!(out[0], single[0]);
+            assert_approx_eq!(out[0], graph[0].data());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "every row must have 2 features")]
+    fn test_forward_eval_batch_ragged() {
+        let model = MLP::new(vec![2, 4, 1]);
+        model.forward_eval_batch(&[vec![0.5, 1.0], vec![0.5], vec![1.0, -1.0]]);
+    }
+
+    #[test]
+    fn test_sequential() {
+        let x = || vec![Parameter::from_scalar(0.5), Parameter::from_scalar(-1.5)];
+        let model = Sequential::new(vec![
+            Box::new(Layer::new(2, 4, false, true)),
+            Box::new(Dropout::new(0.5)),
+            Box::new(Layer::new(4, 1, false, true)),
+        ]);
+        assert_eq!(model.parameters().len(), (2 + 1) * 4 + (4 + 1));
+        let out = model.forward(x());
+        assert_eq!(out.len(), 1);
+        out[0].backward();
+
+        // without dropout the stack is plain layer composition
+        let (first, second) = (Layer::new(2, 4, false, true), Layer::new(4, 1, false, true));
+        let expected = second.forward(first.forward(x()));
+        let mut dropout = Dropout::new(0.5);
+        dropout.set_training(false);
+        let model = Sequential::new(vec![Box::new(first), Box::new(dropout), Box::new(second)]);
+        assert_approx_eq!(model.forward(x())[0].data(), expected[0].data());
+    }
+
+    #[test]
+    fn test_dropout_seeded() {
+        let x = || (1..=8).map(|i| Parameter::from_scalar(i as f32)).collect();
+        let out = Dropout::with_seed(0.25, 11).forward(x());
+        let data: Vec<f32> = out.iter().map(|o| o.data()).collect();
+        // seed 11 drops the first and the last unit, the rest are scaled up
+        for (i, value) in data.iter().enumerate() {
+            if i == 0 || i == 7 {
+                assert_eq!(*value, 0.0);
+            } else {
+                assert_approx_eq!(*value, (i + 1) as f32 / 0.75);
+            }
+        }
+        // the mask is a constant, the input is the only trainable leaf
+        let ops: Vec<Operation> = serialize_graph(&out[0])
+            .nodes
+            .iter()
+            .map(|node| node.op)
+            .collect();
+        assert_eq!(ops, vec![Operation::Init, Operation::Const, Operation::Mul]);
+
+        let again = Dropout::with_seed(0.25, 11).forward(x());
+        assert_eq!(again.iter().map(|o| o.data()).collect::<Vec<f32>>(), data);
+    }
+
+    #[test]
+    fn test_label_smoothing() {
+        let model = MLP::new(vec![2, 2, 1]);
+        // zero weights so the regularization term vanishes
+        model
             .parameters()
+            .iter()
+            .for_each(|param| param.set_data(0.0));
+        let preds = || vec![Parameter::from_scalar(1.0), Parameter::from_scalar(-1.0)];
+        let y = vec![1.0, -1.0];
+
+        let (hard, _) = loss(&model, preds(), &y);
+        let (smoothed, acc) = loss_smoothed(&model, preds(), &y, 0.1);
+        assert_eq!(hard.data(), 0.0);
+        assert!(smoothed.data() > 0.0);
+        assert_approx_eq!(smoothed.data(), 0.1);
+        assert_eq!(acc, 1.0);
+    }
+
+    #[test]
+    fn test_layer_from_weights() {
+        let layer = Layer::from_weights(
+            vec![vec![1.0, 2.0, 3.0], vec![-1.0, 0.5, 0.0]],
+            vec![0.5, -2.0],
+            false,
+        );
+        let x = vec![1.0, -1.0, 2.0];
+        let out = layer.forward(x.iter().map(|xi| Parameter::from_scalar(*xi)).collect());
+        // 1 - 2 + 6 + 0.5, -1 - 0.5 + 0 - 2
+        assert_eq!(out[0].data(), 5.5);
+        assert_eq!(out[1].data(), -3.5);
+
+        let relu = Layer::from_weights(vec![vec![-1.0, 0.5, 0.0]], vec![-2.0], true);
+        assert_eq!(relu.forward_eval(&x), vec![0.0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_layer_from_weights_ragged() {
+        Layer::from_weights(vec![vec![1.0, 2.0], vec![1.0]], vec![0.0, 0.0], false);
+    }
+
+    #[test]
+    fn test_loss_without_regularization() {
+        let model = MLP::new(vec![2, 2, 1]);
+        let preds = || vec![Parameter::from_scalar(0.5), Parameter::from_scalar(2.0)];
+        let y = vec![1.0, -1.0];
+
+        let (total_loss, _) = loss_regularized(&model, preds(), &y, 0.0);
+        // (relu(1 - 0.5) + relu(1 + 2)) / 2
+        assert_eq!(total_loss.data(), 1.75);
+        // the model parameters are not part of the graph
+        total_loss.backward();
+        for param in model.parameters() {
+            assert_eq!(param.grad(), 0.0);
+        }
+
+        let (total_loss, _) = loss_regularized(&model, preds(), &y, 1.0);
+        let sum_sq: f32 = model.parameters().iter().map(|p| p.data().powi(2)).sum();
+        assert_approx_eq!(total_loss.data(), 1.75 + sum_sq);
+    }
+
+    #[test]
+    fn test_partial_fit() {
+        let model = MlpBuilder::new()
+            .input(2)
+            .layer(1, Activation::Linear)
+            .seed(3)
+            .build();
+        let mut rng = StdRng::seed_from_u64(3);
+        let losses: Vec<f32> = (0..400)
+            .map(|_| {
+                let (x1, x2) = (rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0));
+                let y = if x1 + x2 > 0.0 { 1.0 } else { -1.0 };
+                model.partial_fit(&[x1, x2], y, 0.1)
+            })
+            .collect();
+        let mean = |l: &[f32]| l.iter().sum::<f32>() / l.len() as f32;
+        assert!(mean(&losses[300..]) < mean(&losses[..100]));
+    }
+
+    #[test]
+    fn test_pairwise_margin_loss() {
+        let y = vec![1.0, 1.0, -1.0];
+        let preds = |p: [f32; 3]| p.map(Parameter::from_scalar).to_vec();
+
+        let close = pairwise_margin_loss(&preds([2.0, 2.1, -1.0]), &y, 1.0);
+        let far = pairwise_margin_loss(&preds([2.0, 0.5, -1.0]), &y, 1.0);
+        assert_approx_eq!(close.data(), 0.01 / 3.0);
+        assert_approx_eq!(far.data(), 2.25 / 3.0);
+        assert!(close.data() < far.data());
+
+        // different-class pair inside the margin pulls them apart
+        let p = preds([0.2, 0.2, 0.0]);
+        pairwise_margin_loss(&p, &y, 1.0).backward();
+        assert!(p[0].grad() < 0.0);
+        assert!(p[2].grad() > 0.0);
+    }
+
+    #[test]
+    fn test_forward_batch_workers() {
+        let model = MLP::new(vec![2, 8, 1]);
+        let xs: Vec<Vec<f32>> = (0..37).map(|i| vec![i as f32 * 0.1, -0.5]).collect();
+        let sequential = model.forward_batch(&xs, &ThreadConfig { workers: 1 });
+        let parallel = model.forward_batch(&xs, &ThreadConfig { workers: 4 });
+        assert_eq!(sequential.len(), xs.len());
+        assert_eq!(parallel.len(), xs.len());
+        for (s, p) in zip(sequential, parallel) {
+            assert_eq!(s[0].data(), p[0].data());
+        }
+    }
+
+    #[test]
+    fn test_grid_eval() {
+        let model = MLP::new(vec![2, 4, 1]);
+        let grid = model.grid_eval((-1.0, 2.0), (-0.5, 0.5), 5);
+        assert_eq!(grid.len(), 5);
+        assert!(grid.iter().all(|row| row.len() == 5));
+        assert_approx_eq!(grid[0][0], model.forward_eval(&[-1.0, -0.5])[0]);
+        assert_approx_eq!(grid[0][4], model.forward_eval(&[2.0, -0.5])[0]);
+        assert_approx_eq!(grid[4][0], model.forward_eval(&[-1.0, 0.5])[0]);
+        assert_approx_eq!(grid[4][4], model.forward_eval(&[2.0, 0.5])[0]);
+    }
+
+    #[test]
+    fn test_forward_until() {
+        let model = MLP::new(vec![2, 16, 8, 1]);
+        let x = || vec![Parameter::from_scalar(0.3), Parameter::from_scalar(-0.7)];
+        assert_eq!(model.forward_until(x(), 0).len(), 16);
+        assert_eq!(model.forward_until(x(), 1).len(), 8);
+        let last = model.forward_until(x(), 2);
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].data(), model.forward(x())[0].data());
+    }
+
+    #[test]
+    fn test_bias_free_layer() {
+        let layer = Layer::new(3, 4, false, false);
+        assert_eq!(layer.parameters().len(), 3 * 4);
+        assert_eq!(
+            Layer::new(3, 4, false, true).parameters().len(),
+            (3 + 1) * 4
+        );
+
+        // without a bias a zero input maps to zero
+        let out = layer.forward(vec![Parameter::from_scalar(0.0); 3]);
+        assert!(out.iter().all(|o| o.data() == 0.0));
+        assert_eq!(layer.forward_eval(&[0.0; 3]), vec![0.0; 4]);
+    }
+
+    #[test]
+    fn test_top_k_accuracy() {
+        let logits: Vec<Vec<Parameter>> = [
+            [0.1, 0.5, 0.2, 0.9, 0.0],
+            [2.0, 1.0, 0.0, -1.0, -2.0],
+            [0.3, 0.2, 0.1, 0.4, 0.5],
+            [1.0, 3.0, 2.0, 0.0, 0.5],
+        ]
+        .iter()
+        .map(|row| row.iter().map(|l| Parameter::from_scalar(*l)).collect())
+        .collect();
+        let labels = vec![3, 1, 2, 2];
+
+        let top_1 = top_k_accuracy(&logits, &labels, 1);
+        let top_3 = top_k_accuracy(&logits, &labels, 3);
+        assert_eq!(top_1, 0.25);
+        assert_eq!(top_3, 0.75);
+        assert!(top_1 <= top_3);
+        assert_eq!(top_k_accuracy(&logits, &labels, 5), 1.0);
+    }
+
+    #[test]
+    fn test_fit_history() {
+        let (x, y01) = make_moons_seeded(50, true, 0.1, 0);
+        let x: Vec<Vec<f32>> = x.into_iter().map(|(x1, x2)| vec![x1, x2]).collect();
+        let y: Vec<f32> = y01.iter().map(|yi| yi * 2.0 - 1.0).collect();
+        let model = MLP::new(vec![2, 8, 8, 1]);
+        let history = model.fit(&x, &y, 3, 0.5);
+        assert_eq!(history.losses.len(), 3);
+        assert_eq!(history.accuracies.len(), 3);
+        assert_eq!(history.epoch_times_ms.len(), 3);
+        assert!(history.epoch_times_ms.iter().all(|t| *t > 0));
+        assert_eq!(history.weight_deltas.len(), 3);
+        assert!(history.weight_deltas.iter().all(|d| *d > 0.0));
+    }
+
+    #[test]
+    fn test_snapshot_delta_norm() {
+        let a = vec![1.0, -2.0, 0.5];
+        assert_eq!(snapshot_delta_norm(&a, &a), 0.0);
+        assert_approx_eq!(snapshot_delta_norm(&a, &[4.0, 2.0, 0.5]), 5.0);
+    }
+
+    #[test]
+    fn test_fit_timing_breakdown() {
+        let x = vec![vec![1.0, 0.5], vec![-1.0, -0.5]];
+        let model = MLP::new(vec![2, 4, 1]);
+        let history = model.fit(&x, &[1.0, -1.0], 4, 0.1);
+        assert_eq!(history.forward_ms.len(), 4);
+        assert_eq!(history.backward_ms.len(), 4);
+        assert_eq!(history.step_ms.len(), 4);
+        assert!(history.forward_ms.iter().all(|t| *t > 0.0));
+    }
+
+    #[test]
+    fn test_loss_empty() {
+        let model = MLP::new(vec![2, 2, 1]);
+        let (total_loss, acc) = loss_regularized(&model, vec![], &vec![], 0.0);
+        assert_eq!(total_loss.data(), 0.0);
+        assert_eq!(acc, 0.0);
+        total_loss.backward();
+
+        // a model without layers has no parameters to regularize
+        let empty = MLP::new(vec![2]);
+        assert!(empty.parameters().is_empty());
+        let (total_loss, _) = loss(&empty, vec![], &vec![]);
+        assert_eq!(total_loss.data(), 0.0);
+    }
+
+    #[test]
+    fn test_accuracy_threshold() {
+        let preds = vec![Parameter::from_scalar(0.6), Parameter::from_scalar(0.4)];
+        assert_eq!(accuracy(&preds, &[1.0, 0.0], 0.5), 1.0);
+        assert_eq!(accuracy(&preds, &[1.0, 1.0], 0.5), 0.5);
+        // with the default sign rule both count as positive
+        assert_eq!(accuracy(&preds, &[1.0, 1.0], 0.0), 1.0);
+        assert_eq!(accuracy(&[], &[], 0.5), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected one label per prediction")]
+    fn test_accuracy_length_mismatch() {
+        let preds = vec![Parameter::from_scalar(0.6), Parameter::from_scalar(0.4)];
+        accuracy(&preds, &[1.0], 0.5);
+    }
+
+    #[test]
+    fn test_flat_gradients() {
+        let model = MLP::new(vec![2, 3, 1]);
+        let out = model.forward(vec![
+            Parameter::from_scalar(1.0),
+            Parameter::from_scalar(-2.0),
+        ]);
+        out[0].backward();
+        let grads = model.gradients();
+        assert_eq!(grads.len(), model.parameters().len());
+        assert!(zip(grads, model.parameters()).all(|(g, p)| g == p.grad()));
+
+        let snapshot = model.snapshot();
+        model.set_from_flat(&snapshot);
+        assert_eq!(model.snapshot(), snapshot);
+
+        let shifted: Vec<f32> = snapshot.iter().map(|d| d + 1.0).collect();
+        model.set_from_flat(&shifted);
+        assert_eq!(model.snapshot(), shifted);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let raw = vec![vec![1.0, 10.0], vec![2.0, 20.0], vec![6.0, 30.0]];
+        let mut standardized = raw.clone();
+        let (mean, std) = standardize(&mut standardized);
+
+        let model = Sequential::new(vec![
+            Box::new(Normalize::new(mean, std)),
+            Box::new(Layer::new(2, 1, false, true)),
+        ]);
+        assert_eq!(model.parameters().len(), 3);
+        let normalize = &model.modules[0];
+        assert!(normalize.parameters().is_empty());
+        for (x, expected) in zip(raw, standardized) {
+            let out = normalize.forward(x.into_iter().map(Parameter::from_scalar).collect());
+            for (o, e) in zip(out, expected) {
+                assert_approx_eq!(o.data(), e);
+            }
+        }
+
+        // the statistics are constants and a zero std leaves the feature
+        // centered but unscaled
+        let normalize = Normalize::new(vec![1.0, 2.0], vec![0.0, 2.0]);
+        let out = normalize.forward(vec![
+            Parameter::from_scalar(4.0),
+            Parameter::from_scalar(6.0),
+        ]);
+        assert_eq!(out[0].data(), 3.0);
+        assert_eq!(out[1].data(), 2.0);
+        let n_const = serialize_graph(&out[0])
+            .nodes
+            .iter()
+            .filter(|node| node.op == Operation::Const)
+            .count();
+        // mean, the -1 of the subtraction and std
+        assert_eq!(n_const, 3);
+    }
+
+    #[test]
+    fn test_grads_zero_before_backward() {
+        let model = MLP::new(vec![2, 4, 1]);
+        let preds = model.forward(vec![
+            Parameter::from_scalar(0.5),
+            Parameter::from_scalar(1.0),
+        ]);
+        let (total_loss, _) = loss(&model, preds.clone(), &vec![1.0]);
+        model.assert_grads_zero();
+        assert_eq!(preds[0].grad(), 0.0);
+        assert_eq!(total_loss.grad(), 0.0);
+
+        total_loss.backward();
+        assert!(model.parameters().iter().any(|p| p.grad() != 0.0));
+        model.zero_grad();
+        model.assert_grads_zero();
+    }
+
+    #[test]
+    fn test_mae() {
+        let preds: Vec<Parameter> = [1.0, 2.5, -1.0]
             .into_iter()
-            .reduce(|acc, param| acc + param.clone() * param.clone())
-            .unwrap();
-    let total_loss = data_loss + reg_loss;
-    let matches = zip(y, preds).map(|(yi, pi)| (*yi > 0.0) == (pi.data() > 0.0));
-    let n_true = matches
-        .into_iter()
-        .filter(|b| *b)
-        .collect::<Vec<bool>>()
-        .len();
-    let acc = (n_true as f32) / (n as f32);
+            .map(Parameter::from_scalar)
+            .collect();
+        // (0.5 + 0.5 + 2.0) / 3
+        assert_approx_eq!(mae(&preds, &[0.5, 3.0, 1.0]), 1.0);
+        assert_eq!(mae(&preds, &[1.0, 2.5, -1.0]), 0.0);
+    }
 
-    (total_loss, acc)
+    #[test]
+    fn test_constant_predictor() {
+        let model = MLP::constant_predictor(2);
+        assert_eq!(model.parameters().len(), 2);
+        model.set_from_flat(&[0.7, -1.2]);
+        for x in [[0.0, 0.0], [3.0, -5.0]] {
+            let out = model.forward(x.iter().map(|xi| Parameter::from_scalar(*xi)).collect());
+            assert_eq!(out[0].data(), 0.7);
+            assert_eq!(out[1].data(), -1.2);
+            assert_eq!(model.forward_eval(&x), vec![0.7, -1.2]);
+        }
+        assert_eq!(
+            model.forward_eval_batch(&[vec![1.0, 2.0]]),
+            vec![vec![0.7, -1.2]]
+        );
+        // empty rows work the same as in `forward_eval`
+        assert_eq!(model.forward_eval(&[]), vec![0.7, -1.2]);
+        assert_eq!(
+            model.forward_eval_batch(&[vec![], vec![]]),
+            vec![vec![0.7, -1.2]; 2]
+        );
+        assert_eq!(model.predict(&[vec![]]), vec![1.0]);
+    }
+
+    #[test]
+    fn test_clamp_weights() {
+        let model = MLP::new(vec![2, 4, 1]);
+        let out = model.forward(vec![
+            Parameter::from_scalar(3.0),
+            Parameter::from_scalar(-4.0),
+        ]);
+        out[0].backward();
+        model.lr_step(100.0);
+        assert!(model.snapshot().iter().any(|d| d.abs() > 1.0));
+
+        model.clamp_weights(-1.0, 1.0);
+        assert!(model.snapshot().iter().all(|d| (-1.0..=1.0).contains(d)));
+    }
+
+    #[test]
+    fn test_decision_contour() {
+        // output x - y, so the boundary is the line y = x
+        let model = MLP {
+            in_dim: 2,
+            layers: vec![Layer::from_weights(vec![vec![1.0, -1.0]], vec![0.0], false)],
+        };
+        let steps = 21;
+        let cell = 2.0 / (steps - 1) as f32;
+        let contour = model.decision_contour((-1.0, 1.0), (-1.0, 1.0), steps);
+        assert!(!contour.is_empty());
+        assert!(contour.iter().all(|(x, y)| (x - y).abs() <= cell));
+    }
+
+    #[test]
+    fn test_fit_predict() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let x: Vec<Vec<f32>> = (0..40)
+            .map(|i| {
+                // keep the points away from the boundary x1 + x2 = 0
+                let offset = if i % 2 == 0 { 0.5 } else { -0.5 };
+                let x1: f32 = rng.gen_range(-1.0..=1.0);
+                vec![x1, -x1 + offset + rng.gen_range(-0.2..=0.2)]
+            })
+            .collect();
+        let y: Vec<f32> = x
+            .iter()
+            .map(|xi| if xi[0] + xi[1] > 0.0 { 1.0 } else { -1.0 })
+            .collect();
+
+        let model = MlpBuilder::new()
+            .input(2)
+            .layer(1, Activation::Linear)
+            .seed(5)
+            .build();
+        let preds = model.fit_predict(&x, &y, 50, 0.5);
+        assert_eq!(preds.len(), y.len());
+        let n_correct = zip(&preds, &y).filter(|(p, yi)| p == yi).count();
+        assert!(n_correct as f32 / y.len() as f32 >= 0.9);
+    }
+
+    #[test]
+    fn test_parameter_groups() {
+        let model = MLP::new(vec![2, 3, 1]);
+        let groups = model.parameter_groups();
+        assert_eq!(groups[0].0, "weights");
+        assert_eq!(groups[1].0, "biases");
+        assert_eq!(groups[0].1.len(), 2 * 3 + 3);
+        assert_eq!(groups[1].1.len(), 3 + 1);
+
+        let params = model.parameters();
+        let grouped: Vec<Parameter> = groups.into_iter().flat_map(|(_, g)| g).collect();
+        assert_eq!(grouped.len(), params.len());
+        assert!(params.iter().all(|p| grouped.contains(p)));
+    }
+
+    #[test]
+    fn test_loss_accuracy_matches_accuracy() {
+        let model = MLP::new(vec![2, 4, 1]);
+        let x: Vec<Vec<f32>> = (0..10).map(|i| vec![i as f32 * 0.2 - 1.0, 0.3]).collect();
+        let y: Vec<f32> = (0..10)
+            .map(|i| if i % 3 == 0 { 1.0 } else { -1.0 })
+            .collect();
+        let preds: Vec<Parameter> = model
+            .forward_batch(&x, &ThreadConfig { workers: 1 })
+            .into_iter()
+            .flatten()
+            .collect();
+        let (_, acc) = loss(&model, preds.clone(), &y);
+        assert_eq!(acc, accuracy(&preds, &y, 0.0));
+
+        // predict applies the same rule
+        let predicted = model.predict(&x);
+        let agree = zip(&predicted, &preds).all(|(label, p)| sign_match(p.data(), *label, 0.0));
+        assert!(agree);
+    }
+
+    #[test]
+    fn test_loss_margin() {
+        let model = MLP::new(vec![2, 2, 1]);
+        let preds = || vec![Parameter::from_scalar(0.8), Parameter::from_scalar(-1.1)];
+        let y = vec![1.0, -1.0];
+        let (small, _) = loss_margin(&model, preds(), &y, 1.0, 0.0);
+        let (large, _) = loss_margin(&model, preds(), &y, 2.0, 0.0);
+        assert_approx_eq!(small.data(), 0.1);
+        assert_approx_eq!(large.data(), 1.05);
+        assert!(large.data() > small.data());
+
+        let (default, _) = loss(&model, preds(), &y);
+        let (same, _) = loss_margin(&model, preds(), &y, 1.0, 1e-4);
+        assert_approx_eq!(default.data(), same.data());
+
+        // per sample: margin, -target, pred, product, sum and relu; then
+        // the sum over samples and the mean, whose four node 1 / n folds
+        // to a single constant
+        let n_nodes = |p: &Parameter| serialize_graph(p).nodes.len();
+        assert_eq!(n_nodes(&small), 18);
+        let folded = fold_constants(&small);
+        assert_eq!(n_nodes(&folded), 15);
+        let graph = serialize_graph(&folded);
+        assert!(graph.nodes.iter().all(|node| node.op != Operation::Neg));
+        let n_leaves = graph
+            .nodes
+            .iter()
+            .filter(|node| node.op == Operation::Init)
+            .count();
+        assert_eq!(n_leaves, 2);
+    }
+
+    #[test]
+    fn test_from_config() {
+        let cfg = MlpConfig {
+            dims: vec![2, 8, 4, 1],
+            hidden_activation: Activation::Tanh,
+            output_activation: Activation::Sigmoid,
+            init: Init::Xavier,
+            seed: 3,
+        };
+        let model = MLP::from_config(&cfg);
+        assert_eq!(model.dims(), cfg.dims);
+        assert_eq!(
+            model.activations(),
+            vec![Activation::Tanh, Activation::Tanh, Activation::Sigmoid]
+        );
+        assert_eq!(model.parameters().len(), 3 * 8 + 9 * 4 + 5);
+        let bound = (6.0f32 / 10.0).sqrt();
+        assert!(model.layers[0]
+            .parameters()
+            .iter()
+            .all(|p| p.data().abs() <= bound));
+        // seeded, so rebuilding gives the same weights
+        assert_eq!(MLP::from_config(&cfg).snapshot(), model.snapshot());
+
+        let out = model.forward_eval(&[0.3, -0.2]);
+        assert!(out[0] > 0.0 && out[0] < 1.0);
+        let preds = model.forward(vec![
+            Parameter::from_scalar(0.3),
+            Parameter::from_scalar(-0.2),
+        ]);
+        assert_approx_eq!(preds[0].data(), out[0]);
+    }
+
+    #[test]
+    fn test_layer_forward_shares_inputs() {
+        let (in_dim, out_dim) = (100, 100);
+        let layer = Layer::new(in_dim, out_dim, false, true);
+        let x: Vec<Parameter> = (0..in_dim)
+            .map(|i| Parameter::from_scalar(i as f32 / 100.0))
+            .collect();
+        let out = layer.forward(x.clone());
+        // each neuron keeps an input as an operand and in its backward closure
+        let bumps: usize = x.iter().map(|xi| Arc::strong_count(&xi.0) - 1).sum();
+        assert_eq!(bumps, 2 * in_dim * out_dim);
+
+        // the closures are consumed, leaving one handle per neuron
+        out.iter().cloned().sum::<Parameter>().backward();
+        let bumps: usize = x.iter().map(|xi| Arc::strong_count(&xi.0) - 1).sum();
+        assert_eq!(bumps, in_dim * out_dim);
+        // gradients from all neurons reach the same node
+        for (i, xi) in x.iter().enumerate() {
+            let expected: f32 = layer.neurons.iter().map(|n| n.weights[i].data()).sum();
+            assert_approx_eq!(xi.grad(), expected, 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_uniform_sample_seeded() {
+        let sample = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..5)
+                .map(|_| uniform_sample(-2.0..=2.0, &mut rng))
+                .collect::<Vec<f32>>()
+        };
+        assert_eq!(sample(4), sample(4));
+        assert_ne!(sample(4), sample(5));
+        assert!(sample(4).iter().all(|x| (-2.0..=2.0).contains(x)));
+    }
+
+    #[test]
+    fn test_boxed_predictor() {
+        let x = vec![vec![1.0, 0.5], vec![-1.0, -0.5]];
+        let model = MLP::new(vec![2, 4, 1]);
+        model.fit(&x, &[1.0, -1.0], 20, 0.1);
+        let expected = model.forward_eval(&x[0]);
+
+        let predictor: Box<dyn Predictor> = Box::new(model);
+        let out = predictor.predict_raw(&x[0]);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_mlp_builder() {
+        let builder = MlpBuilder::new()
+            .input(2)
+            .layer(16, Activation::ReLU)
+            .layer(1, Activation::Sigmoid)
+            .init(Init::He)
+            .seed(11);
+        let model = builder.build();
+        assert_eq!(model.dims(), vec![2, 16, 1]);
+        assert_eq!(
+            model.activations(),
+            vec![Activation::ReLU, Activation::Sigmoid]
+        );
+        assert_eq!(model.parameters().len(), 3 * 16 + 17);
+        assert_eq!(builder.build().snapshot(), model.snapshot());
+        let out = model.forward_eval(&[0.5, -0.5])[0];
+        assert!(out > 0.0 && out < 1.0);
+    }
+
+    #[test]
+    fn test_reset_parameters() {
+        let builder = MlpBuilder::new()
+            .input(3)
+            .layer(5, Activation::ReLU)
+            .layer(2, Activation::Linear)
+            .init(Init::Xavier);
+        let model = builder.clone().seed(1).build();
+        let handles = model.parameters();
+        model.fit(&[vec![1.0, 2.0, 3.0]], &[1.0, -1.0], 3, 0.1);
+
+        model.reset_parameters(7);
+        assert_eq!(model.snapshot(), builder.seed(7).build().snapshot());
+        assert!(zip(handles, model.parameters()).all(|(a, b)| Arc::ptr_eq(&a.0, &b.0)));
+    }
+
+    #[test]
+    fn test_squared_hinge_loss() {
+        let model = MLP::new(vec![2, 2, 1]);
+        for (pred, label) in [(0.5, 1.0), (2.0, -1.0), (1.5, 1.0), (-0.25, -1.0)] {
+            let (l1, _) = loss_regularized(
+                &model,
+                vec![Parameter::from_scalar(pred)],
+                &vec![label],
+                0.0,
+            );
+            let (l2, _) = squared_hinge_loss(
+                &model,
+                vec![Parameter::from_scalar(pred)],
+                &vec![label],
+                0.0,
+            );
+            assert_approx_eq!(l2.data(), l1.data().powi(2));
+        }
+
+        // the gradient goes to zero from both sides of the margin
+        let grad_at = |pred: f32| {
+            let pred = Parameter::from_scalar(pred);
+            let (total_loss, _) = squared_hinge_loss(&model, vec![pred.clone()], &vec![1.0], 0.0);
+            total_loss.backward();
+            pred.grad()
+        };
+        assert_approx_eq!(grad_at(1.0 - 1e-3), -2e-3, 1e-4);
+        assert_eq!(grad_at(1.0), 0.0);
+        assert_eq!(grad_at(1.0 + 1e-3), 0.0);
+        assert_approx_eq!(grad_at(0.0), -2.0);
+    }
+
+    #[test]
+    fn test_sparsity() {
+        let model = MLP::new(vec![2, 3, 1]);
+        let n = model.parameters().len();
+        let flat: Vec<f32> = (1..=n).map(|i| i as f32 / 10.0).collect();
+        model.set_from_flat(&flat);
+        assert_eq!(model.sparsity(), 0.0);
+        model.prune(0.5);
+        assert_approx_eq!(model.sparsity(), (n / 2) as f32 / n as f32);
+        model.prune(1.0);
+        assert_eq!(model.sparsity(), 1.0);
+    }
+
+    #[test]
+    fn test_r2_score() {
+        let targets = vec![1.0, 2.0, 4.0, 5.0];
+        let perfect: Vec<Parameter> = targets.iter().map(|t| Parameter::from_scalar(*t)).collect();
+        assert_eq!(r2_score(&perfect, &targets), 1.0);
+        let mean_only = vec![Parameter::from_scalar(3.0); 4];
+        assert_approx_eq!(r2_score(&mean_only, &targets), 0.0);
+        assert_eq!(r2_score(&perfect, &[2.0; 4]), 0.0);
+    }
+
+    #[test]
+    fn test_history_to_csv() {
+        let history = History {
+            losses: vec![1.5, 0.75],
+            accuracies: vec![0.5, 1.0],
+            epoch_times_ms: vec![12, 10],
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join("micrograd_history.csv");
+        let path = path.to_str().unwrap();
+        history.to_csv(path).unwrap();
+        let csv = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            rows,
+            vec![
+                "epoch,loss,accuracy,epoch_time_ms",
+                "0,1.5,0.5,12",
+                "1,0.75,1,10"
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "all models must have the same output dimension")]
+    fn test_ensemble_output_mismatch() {
+        Ensemble::new(vec![MLP::new(vec![2, 3, 2]), MLP::new(vec![2, 1])]);
+    }
+
+    #[test]
+    fn test_ensemble_mean() {
+        let models: Vec<MLP> = (0..3)
+            .map(|seed| {
+                MlpBuilder::new()
+                    .input(2)
+                    .layer(4, Activation::Tanh)
+                    .layer(2, Activation::Linear)
+                    .seed(seed)
+                    .build()
+            })
+            .collect();
+        let x = [0.3, -0.7];
+        let outputs: Vec<Vec<f32>> = models.iter().map(|m| m.forward_eval(&x)).collect();
+        let ensemble = Ensemble::new(models);
+
+        let graph_out = ensemble.forward(x.iter().map(|xi| Parameter::from_scalar(*xi)).collect());
+        let predicted = ensemble.predict_raw(&x);
+        for i in 0..2 {
+            let mean = outputs.iter().map(|out| out[i]).sum::<f32>() / 3.0;
+            assert_approx_eq!(graph_out[i].data(), mean);
+            assert_approx_eq!(predicted[i], mean);
+        }
+        // gradients reach every model
+        graph_out[0].backward();
+        assert!(ensemble
+            .models
+            .iter()
+            .all(|m| m.gradients().iter().any(|g| *g != 0.0)));
+    }
+
+    /// Checked by `cargo test --no-default-features`: training compiles and
+    /// runs without `std::thread`.
+    #[cfg(not(feature = "threads"))]
+    #[test]
+    fn test_fit_without_threads() {
+        let x = vec![vec![1.0, 0.5], vec![-1.0, -0.5]];
+        let model = MLP::new(vec![2, 4, 1]);
+        let history = model.fit(&x, &[1.0, -1.0], 3, 0.1);
+        assert_eq!(history.losses.len(), 3);
+        assert_eq!(ThreadConfig::default().workers, 1);
+        assert_eq!(
+            model.forward_batch(&x, &ThreadConfig { workers: 4 }).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_predict_proba_temperature() {
+        let binary = MLP {
+            in_dim: 2,
+            layers: vec![Layer::from_weights(vec![vec![2.0, -1.0]], vec![0.5], false)],
+        };
+        let x = [1.0, 0.5];
+        let sharp = binary.predict_proba(&x, 1.0)[0];
+        let soft = binary.predict_proba(&x, 5.0)[0];
+        assert_approx_eq!(sharp, 1.0 / (1.0 + (-2.0f32).exp()));
+        assert!((soft - 0.5).abs() < (sharp - 0.5).abs());
+        assert!(soft > 0.5);
+
+        let multiclass = MLP {
+            in_dim: 2,
+            layers: vec![Layer::from_weights(
+                vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![-1.0, 0.0]],
+                vec![0.0; 3],
+                false,
+            )],
+        };
+        let sharp = multiclass.predict_proba(&x, 0.5);
+        let soft = multiclass.predict_proba(&x, 10.0);
+        assert_approx_eq!(sharp.iter().sum::<f32>(), 1.0);
+        assert_approx_eq!(soft.iter().sum::<f32>(), 1.0);
+        let spread = |p: &[f32]| p.iter().map(|pi| (pi - 1.0 / 3.0).abs()).sum::<f32>();
+        assert!(spread(&soft) < spread(&sharp));
+    }
+
+    #[test]
+    fn test_bench_forward_backward() {
+        let model = MLP::new(vec![2, 16, 16, 1]);
+        let (forward_ms, backward_ms) = bench_forward_backward(&model, 5);
+        assert!(forward_ms > 0.0);
+        assert!(backward_ms > 0.0);
+        assert_eq!(bench_iterations(&model, 7).len(), 7);
+        model.assert_grads_zero();
+
+        // a model without layers passes its input through
+        let identity = MLP::new(vec![2]);
+        assert_eq!(identity.dims(), vec![2]);
+        assert_eq!(bench_iterations(&identity, 3).len(), 3);
+    }
+
+    #[test]
+    fn test_class_weighted_loss() {
+        let model = MLP::new(vec![2, 2, 1]);
+        let y = vec![-1.0, -1.0, -1.0, 1.0];
+        let pred_grads = |class_weights: (f32, f32)| {
+            let preds: Vec<Parameter> = [0.5, -0.25, 0.0, 0.5]
+                .iter()
+                .map(|p| Parameter::from_scalar(*p))
+                .collect();
+            let (total_loss, _) =
+                class_weighted_loss(&model, preds.clone(), &y, class_weights, 0.0);
+            total_loss.backward();
+            preds.iter().map(|p| p.grad()).collect::<Vec<f32>>()
+        };
+        let unweighted = pred_grads((1.0, 1.0));
+        let weighted = pred_grads((1.0, 3.0));
+        assert_approx_eq!(weighted[3], 3.0 * unweighted[3]);
+        assert_approx_eq!(unweighted[3], -0.25);
+        for i in 0..3 {
+            assert_approx_eq!(weighted[i], unweighted[i]);
+        }
+
+        let preds = vec![Parameter::from_scalar(0.5), Parameter::from_scalar(0.5)];
+        let (plain, _) = loss_regularized(&model, preds.clone(), &vec![-1.0, 1.0], 0.0);
+        let (same, _) = class_weighted_loss(&model, preds, &vec![-1.0, 1.0], (1.0, 1.0), 0.0);
+        assert_approx_eq!(plain.data(), same.data());
+    }
+
+    #[test]
+    fn test_shape_trace() {
+        let model = MLP::new(vec![2, 16, 16, 1]);
+        assert_eq!(model.shape_trace(2), Ok(vec![16, 16, 1]));
+        assert_eq!(
+            model.shape_trace(3),
+            Err("layer 0 expects 2 inputs but gets 3".to_string())
+        );
+
+        let inconsistent = MLP {
+            in_dim: 2,
+            layers: vec![Layer::new(2, 4, true, true), Layer::new(3, 1, false, true)],
+        };
+        assert_eq!(
+            inconsistent.shape_trace(2),
+            Err("layer 1 expects 3 inputs but gets 4".to_string())
+        );
+    }
+
+    #[test]
+    fn test_centralize_gradients() {
+        let model = MLP::new(vec![3, 4, 2]);
+        let out: Parameter = model
+            .forward([0.5, -1.0, 2.0].map(Parameter::from_scalar).to_vec())
+            .into_iter()
+            .sum();
+        out.backward();
+        let bias_grads: Vec<f32> = model
+            .layers
+            .iter()
+            .flat_map(|layer| layer.neurons.iter())
+            .map(|neuron| neuron.bias.as_ref().unwrap().grad())
+            .collect();
+
+        model.centralize_gradients();
+        for (neuron, bias_grad) in zip(
+            model.layers.iter().flat_map(|layer| layer.neurons.iter()),
+            bias_grads,
+        ) {
+            let sum: f32 = neuron.weights.iter().map(|wi| wi.grad()).sum();
+            assert_approx_eq!(sum, 0.0, 1e-5);
+            assert_eq!(neuron.bias.as_ref().unwrap().grad(), bias_grad);
+        }
+    }
+
+    #[test]
+    fn test_centralize_gradients_skips_frozen() {
+        let model = MLP::new(vec![3, 1]);
+        model.set_from_flat(&[0.5, -0.25, 0.01, 0.0]);
+        model.prune(0.5);
+        let weights = model.layers[0].neurons[0].weights.clone();
+        assert!(!weights[2].requires_grad());
+
+        let out: Parameter = model
+            .forward([1.0, 2.0, 3.0].map(Parameter::from_scalar).to_vec())
+            .into_iter()
+            .sum();
+        out.backward();
+        model.centralize_gradients();
+        assert_eq!(weights[2].grad(), 0.0);
+        // the trainable weights had gradients 1 and 2 around a mean of 1.5
+        assert_approx_eq!(weights[0].grad(), -0.5);
+        assert_approx_eq!(weights[1].grad(), 0.5);
+    }
 }
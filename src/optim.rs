@@ -0,0 +1,444 @@
+use rand::{rngs::StdRng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+
+use crate::{autograd::Parameter, nn::MLP};
+
+/// Hook called by optimizers for every parameter update, set with the
+/// optimizer's `set_observer`. Frozen parameters aren't updated, so they
+/// aren't reported.
+pub trait UpdateObserver {
+    /// `param_id` is the index of the parameter in the optimizer's list.
+    fn on_update(&mut self, param_id: u64, old: f32, new: f32, grad: f32);
+}
+
+/// Plain stochastic gradient descent.
+pub struct Sgd {
+    params: Vec<Parameter>,
+    lr: f32,
+    observer: Option<Box<dyn UpdateObserver>>,
+}
+
+impl Sgd {
+    pub fn new(params: Vec<Parameter>, lr: f32) -> Sgd {
+        Sgd {
+            params,
+            lr,
+            observer: None,
+        }
+    }
+    pub fn set_lr(&mut self, lr: f32) {
+        self.lr = lr;
+    }
+    /// Reports every update made by `step` to `observer`.
+    pub fn set_observer(&mut self, observer: impl UpdateObserver + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+    /// Set gradients of all parameters to zero.
+    pub fn zero_grad(&mut self) {
+        for param in self.params.iter_mut() {
+            param.zero_grad();
+        }
+    }
+    /// Takes one gradient step on all trainable parameters.
+    pub fn step(&mut self) {
+        for (i, param) in self.params.iter_mut().enumerate() {
+            if !param.requires_grad() {
+                continue;
+            }
+            let old = param.data();
+            param.lr_step(self.lr);
+            if let Some(observer) = self.observer.as_mut() {
+                observer.on_update(i as u64, old, param.data(), param.grad());
+            }
+        }
+    }
+}
+
+/// Adadelta. Scales every step by the ratio of the running RMS of past
+/// updates to the running RMS of gradients, so it needs no learning rate.
+pub struct Adadelta {
+    params: Vec<Parameter>,
+    rho: f32,
+    eps: f32,
+    sq_grads: Vec<f32>,
+    sq_updates: Vec<f32>,
+    observer: Option<Box<dyn UpdateObserver>>,
+}
+
+impl Adadelta {
+    /// `rho` is the decay rate of the running averages (typically 0.9) and
+    /// `eps` keeps the first steps from being zero (typically 1e-6).
+    pub fn new(params: Vec<Parameter>, rho: f32, eps: f32) -> Adadelta {
+        let n = params.len();
+        Adadelta {
+            params,
+            rho,
+            eps,
+            sq_grads: vec![0.0; n],
+            sq_updates: vec![0.0; n],
+            observer: None,
+        }
+    }
+    /// Reports every update made by `step` to `observer`.
+    pub fn set_observer(&mut self, observer: impl UpdateObserver + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+    /// Set gradients of all parameters to zero.
+    pub fn zero_grad(&mut self) {
+        for param in self.params.iter_mut() {
+            param.zero_grad();
+        }
+    }
+    /// Takes one step on all trainable parameters.
+    pub fn step(&mut self) {
+        for (i, param) in self.params.iter().enumerate() {
+            if !param.requires_grad() {
+                continue;
+            }
+            let grad = param.grad();
+            self.sq_grads[i] = self.rho * self.sq_grads[i] + (1.0 - self.rho) * grad * grad;
+            let update = -((self.sq_updates[i] + self.eps).sqrt()
+                / (self.sq_grads[i] + self.eps).sqrt())
+                * grad;
+            self.sq_updates[i] = self.rho * self.sq_updates[i] + (1.0 - self.rho) * update * update;
+            let old = param.data();
+            param.map_data(|data| data + update);
+            if let Some(observer) = self.observer.as_mut() {
+                observer.on_update(i as u64, old, old + update, grad);
+            }
+        }
+    }
+}
+
+/// Adds Gaussian noise with standard deviation `std` to the gradient of
+/// every trainable parameter; frozen ones keep a zero gradient. Call it
+/// between `backward` and the optimizer step. The same `seed` always gives
+/// the same noise.
+pub fn add_grad_noise(params: &[Parameter], std: f32, seed: u64) {
+    let normal = Normal::new(0.0, std).expect("std must be finite and non-negative");
+    let mut rng = StdRng::seed_from_u64(seed);
+    for param in params.iter().filter(|param| param.requires_grad()) {
+        let noise = normal.sample(&mut rng);
+        param.map_grad(|grad| grad + noise);
+    }
+}
+
+/// Adaptive gradient clipping. Scales the gradient of every trainable
+/// parameter down so that `|grad| <= clip_factor * max(|data|, eps)`; `eps`
+/// keeps parameters near zero from being frozen. Frozen parameters are
+/// skipped.
+pub fn adaptive_clip_grad(params: &[Parameter], clip_factor: f32, eps: f32) {
+    for param in params.iter().filter(|param| param.requires_grad()) {
+        let max_norm = clip_factor * param.data().abs().max(eps);
+        param.map_grad(|grad| grad.clamp(-max_norm, max_norm));
+    }
+}
+
+/// Triangular cyclical learning rate scheduler.
+/// The learning rate climbs linearly from `base_lr` to `max_lr` over
+/// `step_size` epochs and then falls back to `base_lr` over the next
+/// `step_size` epochs.
+pub struct CyclicLR {
+    base_lr: f32,
+    max_lr: f32,
+    step_size: usize,
+}
+
+impl CyclicLR {
+    pub fn new(base_lr: f32, max_lr: f32, step_size: usize) -> CyclicLR {
+        assert!(step_size > 0, "step_size must be positive");
+        CyclicLR {
+            base_lr,
+            max_lr,
+            step_size,
+        }
+    }
+    /// Learning rate for `epoch`.
+    pub fn lr(&self, epoch: usize) -> f32 {
+        let step_size = self.step_size as f32;
+        let cycle_pos = (epoch % (2 * self.step_size)) as f32;
+        // 1.0 at cycle boundaries, 0.0 at the cycle midpoint
+        let x = (cycle_pos - step_size).abs() / step_size;
+        self.base_lr + (self.max_lr - self.base_lr) * (1.0 - x)
+    }
+}
+
+/// Lowers the learning rate when a metric that should decrease, e.g. the
+/// loss, stops improving. After `patience` epochs in a row without a new
+/// best value the learning rate is multiplied by `factor`, but never goes
+/// below `min_lr`.
+pub struct ReduceLROnPlateau {
+    lr: f32,
+    factor: f32,
+    patience: usize,
+    min_lr: f32,
+    best: f32,
+    n_stale: usize,
+}
+
+impl ReduceLROnPlateau {
+    pub fn new(lr: f32, factor: f32, patience: usize, min_lr: f32) -> ReduceLROnPlateau {
+        assert!(0.0 < factor && factor < 1.0, "factor must be in (0, 1)");
+        ReduceLROnPlateau {
+            lr,
+            factor,
+            patience,
+            min_lr,
+            best: f32::INFINITY,
+            n_stale: 0,
+        }
+    }
+    /// Records the metric of an epoch and returns the learning rate to use.
+    pub fn step(&mut self, metric: f32) -> f32 {
+        if metric < self.best {
+            self.best = metric;
+            self.n_stale = 0;
+        } else {
+            self.n_stale += 1;
+            if self.n_stale >= self.patience {
+                self.lr = (self.lr * self.factor).max(self.min_lr);
+                self.n_stale = 0;
+            }
+        }
+        self.lr
+    }
+}
+
+/// Stochastic Weight Averaging. Keeps a running mean of the model weights
+/// over all `update` calls after the first `warmup` ones.
+pub struct Swa {
+    warmup: usize,
+    n_updates: usize,
+    n_averaged: usize,
+    mean: Vec<f32>,
+}
+
+impl Swa {
+    pub fn new(warmup: usize) -> Swa {
+        Swa {
+            warmup,
+            n_updates: 0,
+            n_averaged: 0,
+            mean: vec![],
+        }
+    }
+    /// Adds the current weights of `model` to the average, typically once
+    /// per epoch.
+    pub fn update(&mut self, model: &MLP) {
+        self.n_updates += 1;
+        if self.n_updates <= self.warmup {
+            return;
+        }
+        let snapshot = model.snapshot();
+        if self.n_averaged == 0 {
+            self.mean = snapshot;
+        } else {
+            assert_eq!(snapshot.len(), self.mean.len(), "model changed shape");
+            let n = (self.n_averaged + 1) as f32;
+            for (mean, data) in self.mean.iter_mut().zip(snapshot) {
+                *mean += (data - *mean) / n;
+            }
+        }
+        self.n_averaged += 1;
+    }
+    /// Number of snapshots in the average.
+    pub fn n_averaged(&self) -> usize {
+        self.n_averaged
+    }
+    /// Writes the averaged weights into `model`.
+    pub fn finalize_into(&self, model: &MLP) {
+        assert!(self.n_averaged > 0, "no weights have been averaged yet");
+        model.set_from_flat(&self.mean);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn test_cyclic_lr() {
+        let scheduler = CyclicLR::new(0.1, 1.0, 5);
+        assert_approx_eq!(scheduler.lr(0), 0.1);
+        assert_approx_eq!(scheduler.lr(5), 1.0);
+        assert_approx_eq!(scheduler.lr(10), 0.1);
+        assert_approx_eq!(scheduler.lr(15), 1.0);
+        // linear in between
+        assert_approx_eq!(scheduler.lr(2), 0.1 + 0.9 * 0.4);
+        assert_approx_eq!(scheduler.lr(7), 0.1 + 0.9 * 0.6);
+    }
+
+    /// `(param_id, old, new, grad)` of an update.
+    type Update = (u64, f32, f32, f32);
+
+    /// Records every update, shared with the test through `calls`.
+    #[derive(Clone, Default)]
+    struct RecordingObserver {
+        calls: Rc<RefCell<Vec<Update>>>,
+    }
+
+    impl UpdateObserver for RecordingObserver {
+        fn on_update(&mut self, param_id: u64, old: f32, new: f32, grad: f32) {
+            self.calls.borrow_mut().push((param_id, old, new, grad));
+        }
+    }
+
+    #[test]
+    fn test_update_observer() {
+        let params: Vec<Parameter> = (0..3).map(|i| Parameter::from_scalar(i as f32)).collect();
+        let out: Parameter = params.iter().map(|p| p.clone() * p.clone()).sum();
+        out.backward();
+
+        let frozen = Parameter::from_scalar(4.0);
+        frozen.set_requires_grad(false);
+        let mut all_params = params.clone();
+        all_params.insert(1, frozen);
+
+        let mut optimizer = Sgd::new(all_params, 0.5);
+        let observer = RecordingObserver::default();
+        optimizer.set_observer(observer.clone());
+        optimizer.step();
+        let ids: Vec<u64> = observer.calls.borrow().iter().map(|c| c.0).collect();
+        assert_eq!(ids, vec![0, 2, 3]);
+        for (_, old, new, grad) in observer.calls.borrow().iter() {
+            assert_approx_eq!(*new, old - 0.5 * grad);
+        }
+        optimizer.step();
+        assert_eq!(observer.calls.borrow().len(), 2 * params.len());
+    }
+
+    #[test]
+    fn test_adadelta_observer() {
+        let params = vec![Parameter::from_scalar(1.0), Parameter::from_scalar(-2.0)];
+        params[1].set_requires_grad(false);
+        let out: Parameter = params.iter().map(|p| p.clone() * p.clone()).sum();
+        out.backward();
+
+        let mut optimizer = Adadelta::new(params.clone(), 0.9, 1e-6);
+        let observer = RecordingObserver::default();
+        optimizer.set_observer(observer.clone());
+        optimizer.step();
+        // the frozen parameter isn't updated, so it isn't reported
+        let calls = observer.calls.borrow();
+        assert_eq!(calls.len(), 1);
+        let (id, old, new, grad) = calls[0];
+        assert_eq!((id, old, grad), (0, 1.0, 2.0));
+        assert_eq!(new, params[0].data());
+    }
+
+    #[test]
+    fn test_swa() {
+        let model = MLP::new(vec![2, 3, 1]);
+        let mut swa = Swa::new(1);
+        // skipped by the warmup
+        model.set_from_flat(&[100.0; 13]);
+        swa.update(&model);
+        assert_eq!(swa.n_averaged(), 0);
+
+        let first: Vec<f32> = (0..13).map(|i| -(i as f32)).collect();
+        model.set_from_flat(&first);
+        swa.update(&model);
+        let second: Vec<f32> = (0..13).map(|i| 3.0 * i as f32).collect();
+        model.set_from_flat(&second);
+        swa.update(&model);
+        assert_eq!(swa.n_averaged(), 2);
+
+        swa.finalize_into(&model);
+        for (avg, (a, b)) in model.snapshot().iter().zip(first.iter().zip(second)) {
+            assert_approx_eq!(*avg, (a + b) / 2.0);
+        }
+    }
+
+    #[test]
+    fn test_add_grad_noise() {
+        let grads_after_noise = |std: f32, seed: u64| {
+            let params: Vec<Parameter> = (0..4).map(|i| Parameter::from_scalar(i as f32)).collect();
+            let out: Parameter = params.iter().map(|p| p.clone() * p.clone()).sum();
+            out.backward();
+            add_grad_noise(&params, std, seed);
+            params.iter().map(|p| p.grad()).collect::<Vec<f32>>()
+        };
+        assert_eq!(grads_after_noise(0.0, 1), vec![0.0, 2.0, 4.0, 6.0]);
+
+        let noisy = grads_after_noise(0.5, 7);
+        assert_eq!(noisy, grads_after_noise(0.5, 7));
+        assert_ne!(noisy, grads_after_noise(0.5, 8));
+        assert_ne!(noisy, vec![0.0, 2.0, 4.0, 6.0]);
+
+        let frozen = Parameter::from_scalar(1.0);
+        frozen.set_requires_grad(false);
+        let trainable = Parameter::from_scalar(2.0);
+        (frozen.clone() * trainable.clone()).backward();
+        add_grad_noise(&[frozen.clone(), trainable.clone()], 0.5, 7);
+        assert_eq!(frozen.grad(), 0.0);
+        assert_ne!(trainable.grad(), 1.0);
+    }
+
+    #[test]
+    fn test_adaptive_clip_grad() {
+        let small = Parameter::from_scalar(0.01);
+        let large = Parameter::from_scalar(10.0);
+        let out = (small.clone() + large.clone()) * Parameter::from_scalar(5.0);
+        out.backward();
+        adaptive_clip_grad(&[small.clone(), large.clone()], 0.5, 1e-3);
+        assert_approx_eq!(small.grad(), 0.005);
+        assert_eq!(large.grad(), 5.0);
+
+        // eps bounds the clipping of parameters at zero
+        let zero = Parameter::from_scalar(0.0);
+        (zero.clone() * Parameter::from_scalar(-3.0)).backward();
+        adaptive_clip_grad(std::slice::from_ref(&zero), 0.5, 1e-2);
+        assert_approx_eq!(zero.grad(), -5e-3);
+
+        // frozen parameters are left alone
+        let frozen = Parameter::from_scalar(0.01);
+        frozen.set_requires_grad(false);
+        frozen.map_grad(|_| 3.0);
+        adaptive_clip_grad(std::slice::from_ref(&frozen), 0.5, 1e-3);
+        assert_eq!(frozen.grad(), 3.0);
+    }
+
+    #[test]
+    fn test_adadelta_step() {
+        let param = Parameter::from_scalar(1.0);
+        (param.clone() * Parameter::from_scalar(2.0)).backward();
+        let (rho, eps) = (0.9, 1e-6);
+        let mut optimizer = Adadelta::new(vec![param.clone()], rho, eps);
+        optimizer.step();
+
+        let sq_grad = (1.0 - rho) * 4.0;
+        let update = -(eps.sqrt() / (sq_grad + eps).sqrt()) * 2.0;
+        assert_approx_eq!(param.data(), 1.0 + update, 1e-7);
+
+        // the second step uses the running average of the first update
+        optimizer.step();
+        let sq_update = (1.0 - rho) * update * update;
+        let sq_grad = rho * sq_grad + (1.0 - rho) * 4.0;
+        let second = -((sq_update + eps).sqrt() / (sq_grad + eps).sqrt()) * 2.0;
+        assert_approx_eq!(param.data(), 1.0 + update + second, 1e-7);
+    }
+
+    #[test]
+    fn test_reduce_lr_on_plateau() {
+        let mut scheduler = ReduceLROnPlateau::new(1.0, 0.5, 3, 0.1);
+        // improving
+        assert_eq!(scheduler.step(1.0), 1.0);
+        assert_eq!(scheduler.step(0.8), 1.0);
+        // plateau: drops once after 3 stale epochs
+        assert_eq!(scheduler.step(0.8), 1.0);
+        assert_eq!(scheduler.step(0.9), 1.0);
+        assert_eq!(scheduler.step(0.85), 0.5);
+        assert_eq!(scheduler.step(0.8), 0.5);
+        // improving again resets the count
+        assert_eq!(scheduler.step(0.7), 0.5);
+        assert_eq!(scheduler.step(0.7), 0.5);
+        for _ in 0..10 {
+            scheduler.step(1.0);
+        }
+        assert_eq!(scheduler.step(1.0), 0.1);
+    }
+}
@@ -1,36 +1,290 @@
-use plotters::prelude::*;
-use std::{fs, iter::zip};
+use plotters::{coord::Shift, prelude::*};
+use std::{error::Error, fs, iter::zip, path::Path};
 
-pub fn draw_chart(x: &Vec<(f32, f32)>, y01: &Vec<f32>) -> Result<(), Box<dyn std::error::Error>> {
-    fs::create_dir("plots")?;
-    let root = BitMapBackend::new("plots/test.png", (640, 480)).into_drawing_area();
+use crate::nn::MLP;
+
+const CHART_SIZE: (u32, u32) = (640, 480);
+
+pub fn draw_chart(x: &[(f32, f32)], y01: &[f32]) -> Result<(), Box<dyn Error>> {
+    let x: Vec<(f64, f64)> = x.iter().map(|(x1, x2)| (*x1 as f64, *x2 as f64)).collect();
+    let y01: Vec<f64> = y01.iter().map(|y| *y as f64).collect();
+    draw_chart_f64(&x, &y01, None)
+}
+
+/// `draw_chart` for `f64` data. With `labels`, `labels[i]` is written next
+/// to point `i`.
+pub fn draw_chart_f64(
+    x: &[(f64, f64)],
+    y01: &[f64],
+    labels: Option<&[String]>,
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all("plots")?;
+    let root = BitMapBackend::new("plots/test.png", CHART_SIZE).into_drawing_area();
+    draw_points(&root, x, y01, labels)
+}
+
+fn draw_points<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    x: &[(f64, f64)],
+    y01: &[f64],
+    labels: Option<&[String]>,
+) -> Result<(), Box<dyn Error>>
+where
+    DB::ErrorType: 'static,
+{
+    if let Some(labels) = labels {
+        assert_eq!(labels.len(), x.len(), "expected one label per point");
+    }
     root.fill(&WHITE)?;
 
     let gradient = colorous::VIRIDIS;
-    let mut chart = ChartBuilder::on(&root)
+    let mut chart = ChartBuilder::on(root)
         .caption("moons", ("sans-serif", 24).into_font())
         .margin(5)
         .x_label_area_size(30)
         .y_label_area_size(30)
-        .build_cartesian_2d(-2f32..2f32, -2f32..2f32)?;
+        .build_cartesian_2d(-2f64..2f64, -2f64..2f64)?;
 
     chart.configure_mesh().draw()?;
 
-    let xy = zip(x.clone(), y01);
+    let xy = zip(x.iter().copied(), y01);
+    chart.draw_series(xy.into_iter().map(|(x, y)| {
+        let color = gradient.eval_continuous(*y);
+        Circle::new(x, 3, RGBColor(color.r, color.g, color.b).filled())
+    }))?;
+
+    if let Some(labels) = labels {
+        chart.draw_series(zip(x.iter().copied(), labels).map(|(x, label)| {
+            EmptyElement::at(x) + Text::new(label.clone(), (5, -5), ("sans-serif", 12))
+        }))?;
+    }
+
     chart
-        .draw_series(xy.into_iter().map(|(x, y)| {
-            let color = gradient.eval_continuous((*y).into());
-            Circle::new(x, 3, RGBColor(color.r, color.g, color.b).filled())
-        }))
-        .unwrap();
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+
+    root.present()?;
+
+    Ok(())
+}
+
+/// A named point set for `draw_chart_multi`: name, points and labels.
+pub type Series<'a> = (&'a str, &'a Vec<(f32, f32)>, &'a Vec<f32>);
+
+/// Overlays several labeled point sets, e.g. train and test data, on one
+/// chart written to `path`. Each `(name, x, y)` series gets its own color and
+/// a legend entry; points with a positive label in `y` are filled and the
+/// rest are drawn as rings.
+pub fn draw_chart_multi(series: &[Series], path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.as_ref().parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let root = BitMapBackend::new(path.as_ref(), CHART_SIZE).into_drawing_area();
+    draw_multi(&root, series)
+}
+
+fn draw_multi<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    series: &[Series],
+) -> Result<(), Box<dyn Error>>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+    let mut chart = ChartBuilder::on(root)
+        .caption("moons", ("sans-serif", 24).into_font())
+        .margin(5)
+        .x_label_area_size(30)
+        .y_label_area_size(30)
+        .build_cartesian_2d(-2f32..2f32, -2f32..2f32)?;
+    chart.configure_mesh().draw()?;
+
+    for (i, (name, x, y)) in series.iter().enumerate() {
+        assert_eq!(x.len(), y.len(), "expected one label per point");
+        let color = Palette99::pick(i).to_rgba();
+        chart
+            .draw_series(zip(x.iter(), y.iter()).map(|(xi, yi)| {
+                let style = if *yi > 0.0 {
+                    color.filled()
+                } else {
+                    color.stroke_width(1)
+                };
+                Circle::new(*xi, 3, style)
+            }))?
+            .label(*name)
+            .legend(move |(lx, ly)| Circle::new((lx + 10, ly), 3, color.filled()));
+    }
 
     chart
         .configure_series_labels()
-        .background_style(&WHITE.mix(0.8))
-        .border_style(&BLACK)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
         .draw()?;
 
     root.present()?;
 
     Ok(())
 }
+
+/// Plots the decision surface of `model` on `[-2, 2]^2` with the points `x`
+/// on top, colored by their label in `y` (-1.0 or 1.0). With `mark_errors`
+/// the points are instead green when `model.predict` classifies them
+/// correctly and red when it doesn't. Returns the number of misclassified
+/// points.
+pub fn draw_decision_boundary(
+    model: &MLP,
+    x: &[(f32, f32)],
+    y: &[f32],
+    path: impl AsRef<Path>,
+    mark_errors: bool,
+) -> Result<usize, Box<dyn Error>> {
+    assert_eq!(x.len(), y.len(), "expected one label per point");
+    if let Some(dir) = path.as_ref().parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let root = BitMapBackend::new(path.as_ref(), CHART_SIZE).into_drawing_area();
+    draw_boundary(&root, model, x, y, mark_errors)
+}
+
+fn draw_boundary<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    model: &MLP,
+    x: &[(f32, f32)],
+    y: &[f32],
+    mark_errors: bool,
+) -> Result<usize, Box<dyn Error>>
+where
+    DB::ErrorType: 'static,
+{
+    const STEPS: usize = 100;
+    root.fill(&WHITE)?;
+    let mut chart = ChartBuilder::on(root)
+        .caption("decision boundary", ("sans-serif", 24).into_font())
+        .margin(5)
+        .x_label_area_size(30)
+        .y_label_area_size(30)
+        .build_cartesian_2d(-2f32..2f32, -2f32..2f32)?;
+    chart.configure_mesh().draw()?;
+
+    // one light cell per grid point, blue for positive and orange otherwise
+    let grid = model.grid_eval((-2.0, 2.0), (-2.0, 2.0), STEPS);
+    let cell = 4.0 / (STEPS - 1) as f32;
+    chart.draw_series(grid.iter().enumerate().flat_map(|(i, row)| {
+        row.iter().enumerate().map(move |(j, out)| {
+            let (cx, cy) = (-2.0 + j as f32 * cell, -2.0 + i as f32 * cell);
+            let color = if *out > 0.0 {
+                RGBColor(198, 219, 239)
+            } else {
+                RGBColor(253, 208, 162)
+            };
+            Rectangle::new(
+                [
+                    (cx - cell / 2.0, cy - cell / 2.0),
+                    (cx + cell / 2.0, cy + cell / 2.0),
+                ],
+                color.filled(),
+            )
+        })
+    }))?;
+
+    let inputs: Vec<Vec<f32>> = x.iter().map(|(x1, x2)| vec![*x1, *x2]).collect();
+    let predicted = model.predict(&inputs);
+    let mut n_wrong = 0;
+    let points: Vec<Circle<(f32, f32), u32>> = zip(x, zip(y, predicted))
+        .map(|(xi, (yi, pred))| {
+            let correct = pred == *yi;
+            n_wrong += !correct as usize;
+            let color = match (mark_errors, correct, *yi > 0.0) {
+                (true, true, _) => GREEN,
+                (true, false, _) => RED,
+                (false, _, true) => BLUE,
+                (false, _, false) => RGBColor(230, 85, 13),
+            };
+            Circle::new(*xi, 3, color.filled())
+        })
+        .collect();
+    chart.draw_series(points)?;
+
+    root.present()?;
+
+    Ok(n_wrong)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+    use crate::{autograd::Parameter, math::make_moons_seeded, nn::accuracy};
+
+    /// Renders into memory and counts the pixels that aren't white.
+    fn drawn_pixels(x: &[(f64, f64)], y01: &[f64], labels: Option<&[String]>) -> usize {
+        let mut buffer = vec![0u8; (CHART_SIZE.0 * CHART_SIZE.1 * 3) as usize];
+        {
+            let root = BitMapBackend::with_buffer(&mut buffer, CHART_SIZE).into_drawing_area();
+            draw_points(&root, x, y01, labels).unwrap();
+        }
+        buffer
+            .chunks_exact(3)
+            .filter(|px| px != &[255, 255, 255])
+            .count()
+    }
+
+    #[test]
+    fn test_f64_points_and_labels() {
+        let x = vec![(-1.0, 0.5), (0.25, -0.75), (1.0, 1.0)];
+        let y01 = vec![0.0, 1.0, 0.5];
+        let empty = drawn_pixels(&[], &[], None);
+        let unlabeled = drawn_pixels(&x, &y01, None);
+        assert!(unlabeled > empty);
+
+        let labels: Vec<String> = (0..3).map(|i| format!("point {}", i)).collect();
+        assert!(drawn_pixels(&x, &y01, Some(&labels)) > unlabeled);
+    }
+
+    #[test]
+    fn test_decision_boundary_marks_errors() {
+        let (x, y01) = make_moons_seeded(30, true, 0.2, 1);
+        let y: Vec<f32> = y01.iter().map(|yi| yi * 2.0 - 1.0).collect();
+        let inputs: Vec<Vec<f32>> = x.iter().map(|(x1, x2)| vec![*x1, *x2]).collect();
+        let model = MLP::new(vec![2, 8, 1]);
+        model.fit(&inputs, &y, 5, 0.1);
+
+        let path = std::env::temp_dir().join("micrograd_decision_boundary.png");
+        let n_wrong = draw_decision_boundary(&model, &x, &y, &path, true).unwrap();
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+
+        let preds: Vec<Parameter> = inputs
+            .iter()
+            .map(|xi| Parameter::from_scalar(model.forward_eval(xi)[0]))
+            .collect();
+        let acc = accuracy(&preds, &y, 0.0);
+        assert_approx_eq!(n_wrong as f32 / x.len() as f32, 1.0 - acc);
+    }
+
+    #[test]
+    fn test_chart_multi_legend() {
+        let (train_x, train_y) = make_moons_seeded(20, true, 0.1, 1);
+        let (test_x, test_y) = make_moons_seeded(10, true, 0.1, 2);
+        let series = [("train", &train_x, &train_y), ("test", &test_x, &test_y)];
+
+        let path = std::env::temp_dir().join("micrograd_chart_multi.png");
+        draw_chart_multi(&series, &path).unwrap();
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+
+        let mut svg = String::new();
+        {
+            let root = SVGBackend::with_string(&mut svg, CHART_SIZE).into_drawing_area();
+            draw_multi(&root, &series).unwrap();
+        }
+        // the svg backend puts every text on its own line
+        let texts: Vec<&str> = svg.lines().map(str::trim).collect();
+        assert!(texts.contains(&"train"));
+        assert!(texts.contains(&"test"));
+    }
+}
@@ -0,0 +1,16 @@
+//! Common types for building and training models:
+//! `use micrograd_rs::prelude::*;`
+
+pub use crate::autograd::{
+    backward_multi, batch_mean, batch_std, elementwise_add, elementwise_mul, reduce_max, softmax,
+    softmax_batch, Parameter,
+};
+pub use crate::data::{CsvDataset, Dataset, MoonsDataset};
+pub use crate::math::{make_moons, make_moons_seeded, shuffle, shuffle_xy, standardize};
+pub use crate::nn::{
+    accuracy, class_weighted_loss, loss, loss_regularized, loss_smoothed, squared_hinge_loss,
+    Activation, Dropout, Ensemble, History, Init, Layer, MlpBuilder, MlpConfig, Module, Neuron,
+    Normalize, Predictor, Sequential, ThreadConfig, MLP,
+};
+pub use crate::optim::{Adadelta, CyclicLR, ReduceLROnPlateau, Sgd, Swa, UpdateObserver};
+pub use crate::tensor::Tensor1d;
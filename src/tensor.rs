@@ -0,0 +1,116 @@
+use std::{
+    iter::zip,
+    ops::{Add, Mul, Sub},
+};
+
+use crate::autograd::Parameter;
+
+/// A vector of Parameters with elementwise arithmetic. Binary ops panic if
+/// the lengths differ.
+#[derive(Clone, Debug)]
+pub struct Tensor1d(pub Vec<Parameter>);
+
+impl Tensor1d {
+    /// Tensor of new trainable leaves.
+    pub fn from_scalars(data: &[f32]) -> Tensor1d {
+        Tensor1d(data.iter().map(|d| Parameter::from_scalar(*d)).collect())
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Data of every element.
+    pub fn data(&self) -> Vec<f32> {
+        self.0.iter().map(|p| p.data()).collect()
+    }
+    /// Gradient of every element.
+    pub fn grad(&self) -> Vec<f32> {
+        self.0.iter().map(|p| p.grad()).collect()
+    }
+    /// Sum of all elements.
+    pub fn sum(&self) -> Parameter {
+        self.0.iter().cloned().sum()
+    }
+    /// Inner product with `other`.
+    pub fn dot(&self, other: &Tensor1d) -> Parameter {
+        (self.clone() * other.clone()).sum()
+    }
+    /// ReLU of every element.
+    pub fn relu(&self) -> Tensor1d {
+        Tensor1d(self.0.iter().map(|p| p.relu_ref()).collect())
+    }
+    fn zip_with(self, other: Tensor1d, f: impl Fn(Parameter, Parameter) -> Parameter) -> Tensor1d {
+        assert_eq!(self.len(), other.len(), "tensors must have the same length");
+        Tensor1d(zip(self.0, other.0).map(|(a, b)| f(a, b)).collect())
+    }
+}
+
+impl From<Vec<Parameter>> for Tensor1d {
+    fn from(params: Vec<Parameter>) -> Tensor1d {
+        Tensor1d(params)
+    }
+}
+
+impl Add for Tensor1d {
+    type Output = Tensor1d;
+    fn add(self, other: Tensor1d) -> Tensor1d {
+        self.zip_with(other, |a, b| a + b)
+    }
+}
+
+impl Sub for Tensor1d {
+    type Output = Tensor1d;
+    fn sub(self, other: Tensor1d) -> Tensor1d {
+        self.zip_with(other, |a, b| a - b)
+    }
+}
+
+impl Mul for Tensor1d {
+    type Output = Tensor1d;
+    fn mul(self, other: Tensor1d) -> Tensor1d {
+        self.zip_with(other, |a, b| a * b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_add_relu_sum() {
+        let a = Tensor1d::from_scalars(&[1.0, -2.0, 0.5]);
+        let b = Tensor1d::from_scalars(&[0.5, 1.0, -1.5]);
+        let c = a.clone() + b.clone();
+        assert_eq!(c.data(), vec![1.5, -1.0, -1.0]);
+        let out = c.relu().sum();
+        out.backward();
+        assert_approx_eq!(out.data(), 1.5);
+        // only the first element is positive after the addition
+        assert_eq!(a.grad(), vec![1.0, 0.0, 0.0]);
+        assert_eq!(b.grad(), vec![1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_dot_and_sub() {
+        let a = Tensor1d::from_scalars(&[1.0, 2.0, 3.0]);
+        let b = Tensor1d::from_scalars(&[4.0, -5.0, 6.0]);
+        let dot = a.dot(&b);
+        dot.backward();
+        assert_approx_eq!(dot.data(), 12.0);
+        assert_eq!(a.grad(), b.data());
+        assert_eq!(b.grad(), a.data());
+        assert_eq!((a.clone() - b).data(), vec![-3.0, 7.0, -3.0]);
+        assert_eq!(a.len(), 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_length_mismatch() {
+        let _ = Tensor1d::from_scalars(&[1.0]) + Tensor1d::from_scalars(&[1.0, 2.0]);
+    }
+}
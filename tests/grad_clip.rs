@@ -0,0 +1,29 @@
+// The clip bound is global, so this runs in its own test binary where no
+// other test can observe it.
+use micrograd_rs::autograd::{clear_grad_clip, set_grad_clip, Parameter};
+
+#[test]
+fn test_grad_clip() {
+    let x = Parameter::from_scalar(2.0);
+    let w = Parameter::from_scalar(3.0);
+    let big = Parameter::constant(1000.0);
+    set_grad_clip(5.0);
+    // upstream gradient of 1000 into w * x
+    let out = (w.clone() * x.clone()) * big;
+    out.backward();
+    clear_grad_clip();
+    assert_eq!(x.grad(), 5.0);
+    assert_eq!(w.grad(), 5.0);
+
+    // backward_fresh clips the same way
+    set_grad_clip(5.0);
+    out.backward_fresh();
+    clear_grad_clip();
+    assert_eq!(x.grad(), 5.0);
+    assert_eq!(w.grad(), 5.0);
+
+    let x = Parameter::from_scalar(2.0);
+    let out = x.clone() * Parameter::constant(1000.0);
+    out.backward();
+    assert_eq!(x.grad(), 1000.0);
+}
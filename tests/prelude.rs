@@ -0,0 +1,35 @@
+use micrograd_rs::prelude::*;
+
+#[test]
+fn test_train_with_prelude() {
+    let (x, y01) = make_moons_seeded(20, true, 0.1, 7);
+    let x: Vec<Vec<f32>> = x.into_iter().map(|(x1, x2)| vec![x1, x2]).collect();
+    let y: Vec<f32> = y01.iter().map(|yi| yi * 2.0 - 1.0).collect();
+
+    let model = MLP::new(vec![2, 8, 1]);
+    let mut optimizer = Sgd::new(model.parameters(), 0.1);
+    let scheduler = CyclicLR::new(0.05, 0.5, 2);
+    for epoch in 0..4 {
+        let preds: Vec<Parameter> = x
+            .iter()
+            .flat_map(|xi| model.forward(xi.iter().map(|v| Parameter::from_scalar(*v)).collect()))
+            .collect();
+        let (total_loss, acc) = loss(&model, preds, &y);
+        assert!((0.0..=1.0).contains(&acc));
+        optimizer.zero_grad();
+        total_loss.backward();
+        optimizer.set_lr(scheduler.lr(epoch));
+        optimizer.step();
+    }
+    let history: History = model.fit(&x, &y, 2, 0.1);
+    assert_eq!(history.losses.len(), 2);
+}
+
+#[test]
+fn test_crate_root_parameter() {
+    fn takes_autograd_parameter(p: micrograd_rs::autograd::Parameter) -> f32 {
+        p.data()
+    }
+    let p: micrograd_rs::Parameter = micrograd_rs::Parameter::from_scalar(1.5);
+    assert_eq!(takes_autograd_parameter(p), 1.5);
+}